@@ -0,0 +1,345 @@
+use std::{collections::BTreeMap, error::Error, time::Duration, time::Instant};
+
+use clap::Parser;
+use futures::{SinkExt, StreamExt};
+use reqwest::{Client, Method, Response, StatusCode, Url};
+use serde::{Deserialize, Serialize};
+use shared::{
+    path_matches,
+    protocol::{self, ClientMessage, Envelope, ServerMessage, TunnelResponse},
+    repro::{self, Format},
+    CapturedBody, CapturedRequest,
+};
+use tokio_tungstenite::{
+    connect_async,
+    tungstenite::{
+        client::IntoClientRequest,
+        http::HeaderValue,
+        protocol::{frame::coding::CloseCode, CloseFrame},
+        Message,
+    },
+};
+
+static AUTH_HEADER: &str = "X-Auth";
+
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+/// Forwards requests captured by a request-delivery server to a local service.
+#[derive(Parser)]
+#[command(version)]
+struct Args {
+    /// Base URL of the request-delivery server
+    #[arg(
+        short,
+        long,
+        env = "RELAY_SERVER",
+        default_value = "http://localhost:18234"
+    )]
+    server: Url,
+    /// URL of the local service, the captured path and query are appended to it
+    #[arg(short = 'T', long, env = "RELAY_TARGET")]
+    target: Url,
+    /// Endpoint to reuse, or to register if it does not exist yet. A random
+    /// endpoint is registered if omitted
+    #[arg(short, long, env = "RELAY_ID")]
+    id: Option<String>,
+    /// Token of the endpoint
+    #[arg(short, long, env = "RELAY_TOKEN", default_value = "")]
+    token: String,
+    /// Only forward requests sent to this sub-path (or below) of the endpoint
+    #[arg(short, long, env = "RELAY_PATH")]
+    path: Option<String>,
+    /// Print a reproduction of every forwarded request: curl, httpie or reqwest
+    #[arg(short, long, env = "RELAY_REPRO")]
+    repro: Option<Format>,
+    /// Return the responses of the local service to the callers instead of the
+    /// response configured on the server
+    #[arg(long, env = "RELAY_RESPOND")]
+    respond: bool,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Endpoint {
+    id: String,
+    #[serde(default)]
+    token: String,
+}
+
+enum Disconnect {
+    /// The endpoint expired or the client closed the connection, no point in reconnecting
+    Final(String),
+    Retry(String),
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn Error>> {
+    let args = Args::parse();
+    let client = Client::new();
+
+    let endpoint = endpoint(&client, &args).await?;
+    println!(
+        "Relaying {} to {}",
+        args.server.join(&format!("send/{}", endpoint.id))?,
+        args.target
+    );
+    if !endpoint.token.is_empty() {
+        println!("Token: {}", endpoint.token);
+    }
+
+    loop {
+        match relay(&client, &args, &endpoint).await {
+            Ok(Disconnect::Final(reason)) => {
+                println!("Connection closed: {reason}");
+                return Ok(());
+            }
+            Ok(Disconnect::Retry(reason)) => eprintln!("Connection lost: {reason}"),
+            Err(e) => eprintln!("Connection failed: {e}"),
+        }
+        eprintln!("Reconnecting in {} s", RECONNECT_DELAY.as_secs());
+        tokio::time::sleep(RECONNECT_DELAY).await;
+    }
+}
+
+/// Reuses the endpoint given on the command line if the token is accepted,
+/// otherwise registers it (or a random one).
+async fn endpoint(client: &Client, args: &Args) -> Result<Endpoint, Box<dyn Error>> {
+    let Some(id) = &args.id else {
+        let res = client
+            .post(args.server.join("register/random")?)
+            .send()
+            .await?
+            .error_for_status()?;
+        return Ok(res.json().await?);
+    };
+
+    let res = client
+        .head(args.server.join(&format!("validate/{id}"))?)
+        .header(AUTH_HEADER, &args.token)
+        .send()
+        .await?;
+    match res.status() {
+        StatusCode::OK => Ok(Endpoint {
+            id: id.clone(),
+            token: args.token.clone(),
+        }),
+        StatusCode::NOT_FOUND => {
+            let res = client
+                .post(args.server.join("register")?)
+                .json(&Endpoint {
+                    id: id.clone(),
+                    token: args.token.clone(),
+                })
+                .send()
+                .await?
+                .error_for_status()?;
+            Ok(res.json().await?)
+        }
+        status => Err(format!("Endpoint {id} rejected the token ({status})").into()),
+    }
+}
+
+async fn relay(
+    client: &Client,
+    args: &Args,
+    endpoint: &Endpoint,
+) -> Result<Disconnect, Box<dyn Error>> {
+    let mut url = args.server.join(&format!("connect/{}", endpoint.id))?;
+    let scheme = if url.scheme() == "https" { "wss" } else { "ws" };
+    url.set_scheme(scheme)
+        .map_err(|_| format!("Cannot connect to {url}"))?;
+    url.query_pairs_mut()
+        .append_pair("v", &protocol::VERSION.to_string());
+    if args.respond {
+        url.query_pairs_mut().append_pair("respond", "true");
+    }
+
+    let mut request = url.as_str().into_client_request()?;
+    request
+        .headers_mut()
+        .insert(AUTH_HEADER, HeaderValue::from_str(&endpoint.token)?);
+
+    let (mut socket, _) = connect_async(request).await?;
+    println!("Connected");
+
+    while let Some(message) = socket.next().await {
+        match message? {
+            Message::Text(text) => {
+                match serde_json::from_str::<Envelope<ServerMessage<CapturedRequest>>>(&text) {
+                    Ok(Envelope {
+                        message:
+                            ServerMessage::Request {
+                                id,
+                                respond,
+                                request,
+                                ..
+                            },
+                        ..
+                    }) => {
+                        if args
+                            .path
+                            .as_deref()
+                            .is_none_or(|prefix| path_matches(prefix, &request.path))
+                        {
+                            let response = forward(client, args, endpoint, request).await;
+                            if respond {
+                                let answer = Envelope::new(
+                                    protocol::VERSION,
+                                    ClientMessage::Response(answer(id, response).await),
+                                );
+                                socket
+                                    .send(Message::Text(serde_json::to_string(&answer)?))
+                                    .await?;
+                            }
+                        }
+                    }
+                    Ok(Envelope {
+                        message: ServerMessage::Error { message },
+                        ..
+                    }) => eprintln!("Server error: {message}"),
+                    // The close frame that follows is handled below.
+                    Ok(_) => {}
+                    Err(_) => eprintln!("Unexpected message: {text}"),
+                }
+            }
+            Message::Close(frame) => return Ok(disconnect(frame)),
+            _ => {}
+        }
+    }
+    Ok(Disconnect::Retry("Stream ended".to_owned()))
+}
+
+/// Fetches a body the server spooled to disk instead of sending it inline.
+async fn spooled_body(
+    client: &Client,
+    args: &Args,
+    endpoint: &Endpoint,
+    download: &str,
+) -> Result<Vec<u8>, Box<dyn Error>> {
+    let res = client
+        .get(args.server.join(download)?)
+        .header(AUTH_HEADER, &endpoint.token)
+        .send()
+        .await?
+        .error_for_status()?;
+    Ok(res.bytes().await?.to_vec())
+}
+
+fn disconnect(frame: Option<CloseFrame>) -> Disconnect {
+    match frame {
+        Some(CloseFrame {
+            code: CloseCode::Library(4001),
+            ..
+        }) => Disconnect::Final("Endpoint expired".to_owned()),
+        Some(CloseFrame {
+            code: CloseCode::Library(4002),
+            ..
+        }) => Disconnect::Final("Endpoint deleted or token rotated".to_owned()),
+        Some(CloseFrame {
+            code: CloseCode::Away,
+            ..
+        }) => Disconnect::Retry("Server shutting down".to_owned()),
+        Some(frame) => Disconnect::Final(format!("{} {}", frame.code, frame.reason)),
+        None => Disconnect::Final("Closed by server".to_owned()),
+    }
+}
+
+/// Turns the response of the local service into the answer for the caller,
+/// `502 Bad Gateway` if the request failed.
+async fn answer(id: String, response: Option<Response>) -> TunnelResponse {
+    let mut answer = TunnelResponse {
+        id,
+        status: StatusCode::BAD_GATEWAY.as_u16(),
+        headers: BTreeMap::new(),
+        body: None,
+    };
+    let Some(response) = response else {
+        return answer;
+    };
+    let status = response.status().as_u16();
+    let mut headers = BTreeMap::<String, Vec<String>>::new();
+    for (name, value) in response.headers() {
+        headers
+            .entry(name.to_string())
+            .or_default()
+            .push(String::from_utf8_lossy(value.as_bytes()).into_owned());
+    }
+    match response.bytes().await {
+        Ok(bytes) => {
+            answer.status = status;
+            answer.headers = headers;
+            answer.body = Some(CapturedBody::new(&bytes));
+        }
+        Err(e) => eprintln!("Could not read response to {}: {e}", answer.id),
+    }
+    answer
+}
+
+/// Sends the captured request to the local service.
+async fn forward(
+    client: &Client,
+    args: &Args,
+    endpoint: &Endpoint,
+    mut captured: CapturedRequest,
+) -> Option<Response> {
+    let mut url = args.target.as_str().trim_end_matches('/').to_owned();
+    if captured.path != "/" {
+        url.push_str(&captured.path);
+    }
+    if let Some(query) = captured.query() {
+        url.push('?');
+        url.push_str(query);
+    }
+
+    let method = match Method::from_bytes(captured.method.as_bytes()) {
+        Ok(method) => method,
+        Err(_) => {
+            eprintln!("Skipping request with invalid method {}", captured.method);
+            return None;
+        }
+    };
+
+    if let Some(verdict) = captured.signature.as_deref().filter(|v| *v != "valid") {
+        eprintln!("Signature of {} is {verdict}", captured.id);
+    }
+    if captured.complete == Some(false) {
+        eprintln!("Body of {} was truncated by the server", captured.id);
+    }
+
+    let mut request = client.request(method.clone(), &url);
+    for (name, value) in captured.end_to_end_headers() {
+        request = request.header(name, value);
+    }
+    let body = match captured.body.as_ref().and_then(|b| b.download.as_deref()) {
+        Some(download) => match spooled_body(client, args, endpoint, download).await {
+            Ok(body) => body,
+            Err(e) => {
+                eprintln!("Could not download body of {}: {e}", captured.id);
+                return None;
+            }
+        },
+        None => captured.body_bytes(),
+    };
+    if let Some(format) = args.repro {
+        if let Some(spooled) = captured.body.as_mut().filter(|b| b.download.is_some()) {
+            spooled.inline(&body);
+        }
+        print!("{}", repro::render(&captured, format));
+    }
+    request = request.body(body);
+
+    let start = Instant::now();
+    match request.send().await {
+        Ok(res) => {
+            println!(
+                "{method} {url} -> {} ({} ms)",
+                res.status(),
+                start.elapsed().as_millis()
+            );
+            Some(res)
+        }
+        Err(e) => {
+            eprintln!("{method} {url} failed: {e}");
+            None
+        }
+    }
+}
@@ -1,5 +1,16 @@
-use crate::{AUTH_HEADER, MY_EPOCH};
+use crate::{migrations, AUTH_HEADER, CONFIG, MY_EPOCH};
+use std::{marker::PhantomData, sync::OnceLock};
+
+use argon2::{
+    password_hash::{
+        rand_core::{OsRng, RngCore},
+        PasswordHash, PasswordHasher, PasswordVerifier, SaltString,
+    },
+    Argon2,
+};
+use hmac::{Hmac, Mac};
 use nanoid::nanoid;
+use rocket::tokio::task::spawn_blocking;
 use rocket::{fairing::AdHoc, Build, Rocket};
 use rocket::{
     http::Status,
@@ -7,12 +18,23 @@ use rocket::{
     Request,
 };
 use rocket_db_pools::{
-    sqlx::{self, sqlite::SqliteRow, FromRow, Row},
+    sqlx::{self, SqliteConnection},
     Connection, Database,
 };
 use serde::{Deserialize, Serialize};
+use sha2::Sha256;
 use shared::custom_timestamp;
 
+type HmacSha256 = Hmac<Sha256>;
+
+/// Name of the key for generated tokens in the `secrets` table.
+pub const TOKEN_KEY: &str = "token_key";
+
+/// Prefix of token hashes made with [`hash_generated_token`].
+const KEYED_PREFIX: &str = "hmac-sha256$";
+
+static KEY: OnceLock<Vec<u8>> = OnceLock::new();
+
 #[derive(Database)]
 #[database("auth")]
 pub struct AuthDb(sqlx::SqlitePool);
@@ -20,37 +42,65 @@ pub struct AuthDb(sqlx::SqlitePool);
 pub fn attach_db(rocket: Rocket<Build>) -> Rocket<Build> {
     let rocket = rocket.attach(AuthDb::init());
 
-    rocket.attach(AdHoc::try_on_ignite("AuthDB Init", db_setup_internal))
+    rocket
+        .attach(AdHoc::try_on_ignite("AuthDB Migrations", migrations::run))
+        .attach(AdHoc::try_on_ignite("Token Key", load_key))
 }
 
-async fn db_setup_internal(rocket: Rocket<Build>) -> Result<Rocket<Build>, Rocket<Build>> {
-    let db = AuthDb::fetch(&rocket).unwrap();
-    if let Err(e) = sqlx::query("DROP TABLE IF EXISTS auth;")
-        .execute(&**db)
+async fn load_key(rocket: Rocket<Build>) -> Result<Rocket<Build>, Rocket<Build>> {
+    let Some(db) = AuthDb::fetch(&rocket) else {
+        return Err(rocket);
+    };
+    match sqlx::query_scalar::<_, Vec<u8>>("SELECT value FROM secrets WHERE name = ?;")
+        .bind(TOKEN_KEY)
+        .fetch_one(&**db)
         .await
-        .and(
-            sqlx::query(
-                "CREATE TABLE IF NOT EXISTS auth (
-                id TEXT PRIMARY KEY,
-                token TEXT,
-                ts INTEGER)",
-            )
-            .execute(&**db)
-            .await,
-        )
     {
-        eprintln!("Could not execute setup for AuthDB: {e:?}");
-        Err(rocket)
-    } else {
-        Ok(rocket)
+        Ok(key) => {
+            KEY.get_or_init(|| key);
+            Ok(rocket)
+        }
+        Err(e) => {
+            eprintln!("Could not load token key: {e}");
+            Err(rocket)
+        }
     }
 }
 
+pub fn generate_key() -> [u8; 32] {
+    let mut key = [0; 32];
+    OsRng.fill_bytes(&mut key);
+    key
+}
+
+fn token_mac(token: &str) -> HmacSha256 {
+    let key = KEY.get().expect("Token key not loaded");
+    let mut mac = HmacSha256::new_from_slice(key).expect("HMAC accepts any key length");
+    mac.update(token.as_bytes());
+    mac
+}
+
+/// An endpoint registration. `token` grants full access to the endpoint. The
+/// optional `send_token` and `read_token` only allow posting to `/send/<id>`
+/// respectively subscribing to it; when omitted, `token` is required instead,
+/// when empty, the action needs no credential at all.
 #[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
 pub struct Auth {
     id: String,
     #[serde(default)]
     token: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    send_token: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    read_token: Option<String>,
+    /// Seconds of inactivity after which the endpoint expires, capped by
+    /// `max_age`. Sending to it or connecting to it restarts the countdown.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    ttl: Option<u64>,
+    /// Maximum body size captured for the endpoint, capped by the server limit.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    body_limit: Option<u64>,
 }
 
 impl Auth {
@@ -58,30 +108,144 @@ impl Auth {
         Auth {
             id: nanoid!(),
             token: nanoid!(),
+            send_token: None,
+            read_token: None,
+            ttl: None,
+            body_limit: None,
         }
     }
+}
+
+/// Caps a requested time-to-live at `max_age`, which is also the default.
+pub fn ttl(requested: Option<u64>) -> i64 {
+    let max = CONFIG.max_age();
+    requested.map_or(max, |ttl| (ttl.min(i64::MAX as u64) as i64).min(max))
+}
 
-    pub fn id(&self) -> &str {
-        &self.id
+/// Postpones the expiry of an endpoint by its time-to-live.
+pub async fn touch(db: &mut SqliteConnection, id: &str) -> sqlx::Result<()> {
+    sqlx::query("UPDATE auth SET expires = ? + ttl WHERE id = ?;")
+        .bind(custom_timestamp(*MY_EPOCH))
+        .bind(id)
+        .execute(db)
+        .await?;
+    Ok(())
+}
+
+/// Hashes an endpoint token for storage. An empty token stays empty, marking
+/// the endpoint as open.
+pub fn hash_token(token: &str) -> Result<String, argon2::password_hash::Error> {
+    if token.is_empty() {
+        return Ok(String::new());
     }
+    let salt = SaltString::generate(&mut OsRng);
+    Ok(Argon2::default()
+        .hash_password(token.as_bytes(), &salt)?
+        .to_string())
 }
 
-impl FromRow<'_, SqliteRow> for Auth {
-    fn from_row(row: &SqliteRow) -> sqlx::Result<Self> {
-        Ok(Self {
-            id: row.try_get("id")?,
-            token: row.try_get("token")?,
+/// Hashes a token generated by the server. These are random enough that a
+/// keyed hash is as safe as argon2, at a fraction of its cost.
+pub fn hash_generated_token(token: &str) -> String {
+    format!(
+        "{KEYED_PREFIX}{}",
+        hex::encode(token_mac(token).finalize().into_bytes())
+    )
+}
+
+/// Verifies a token against a stored hash in constant time. Argon2 hashes of
+/// chosen tokens take tens of milliseconds of CPU time and are verified on the
+/// blocking pool, keyed hashes of generated tokens are cheap.
+async fn verify_token(hash: String, token: String) -> bool {
+    if hash.is_empty() {
+        return true;
+    }
+    // Nothing hashes to a non-empty hash, no need to spend time on it.
+    if token.is_empty() {
+        return false;
+    }
+    if let Some(keyed) = hash.strip_prefix(KEYED_PREFIX) {
+        return hex::decode(keyed)
+            .is_ok_and(|keyed| token_mac(&token).verify_slice(&keyed).is_ok());
+    }
+    spawn_blocking(move || {
+        PasswordHash::new(&hash).is_ok_and(|hash| {
+            Argon2::default()
+                .verify_password(token.as_bytes(), &hash)
+                .is_ok()
         })
+    })
+    .await
+    .unwrap_or(false)
+}
+
+/// Decides a check of `token` for the scope `T` from the hashes stored for the
+/// endpoint. `scoped_tokens` tells whether it has a send or read token.
+async fn authorize<T: Scope>(
+    hash: String,
+    scoped: Option<String>,
+    scoped_tokens: bool,
+    token: &str,
+) -> Result<(), Status> {
+    if let Some(scoped) = scoped {
+        if verify_token(scoped, token.to_owned()).await {
+            return Ok(());
+        }
+        if hash.is_empty() {
+            return Err(Status::Unauthorized);
+        }
+    }
+    // Without an owner token, an endpoint with scoped tokens has no owner
+    // rather than being owned by everyone.
+    if T::COLUMN.is_none() && hash.is_empty() && (scoped_tokens || !T::OPEN) {
+        return Err(Status::Unauthorized);
+    }
+    if verify_token(hash, token.to_owned()).await {
+        Ok(())
+    } else {
+        Err(Status::Unauthorized)
     }
 }
 
-pub struct AuthService<const ALLOW_QUERY: bool = false> {
+/// What a checked token is allowed to do with an endpoint.
+pub trait Scope: Send + Sync + 'static {
+    /// Column of the scoped token hash, falling back to `token_hash` if NULL.
+    const COLUMN: Option<&'static str>;
+    /// Whether an endpoint without owner token grants the scope to everyone.
+    const OPEN: bool = true;
+}
+
+pub struct Sender;
+pub struct Subscriber;
+pub struct Owner;
+/// Revoking credentials, which always takes the owner token.
+pub struct Credentials;
+
+impl Scope for Sender {
+    const COLUMN: Option<&'static str> = Some("send_hash");
+}
+
+impl Scope for Subscriber {
+    const COLUMN: Option<&'static str> = Some("read_hash");
+}
+
+impl Scope for Owner {
+    const COLUMN: Option<&'static str> = None;
+}
+
+impl Scope for Credentials {
+    const COLUMN: Option<&'static str> = None;
+    const OPEN: bool = false;
+}
+
+pub struct AuthService<S: Scope = Sender, const ALLOW_QUERY: bool = false> {
     token: String,
     db: Connection<AuthDb>,
+    scope: PhantomData<S>,
 }
 
 #[rocket::async_trait]
-impl<'r, const ALLOW_QUERY: bool> FromRequest<'r> for AuthService<ALLOW_QUERY> {
+impl<'r, S: Scope, const ALLOW_QUERY: bool> FromRequest<'r> for AuthService<S, ALLOW_QUERY> {
     type Error = ();
 
     async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error> {
@@ -97,16 +261,18 @@ impl<'r, const ALLOW_QUERY: bool> FromRequest<'r> for AuthService<ALLOW_QUERY> {
             return Outcome::Success(AuthService {
                 token: "".to_owned(),
                 db,
+                scope: PhantomData,
             });
         };
         Outcome::Success(AuthService {
             token: token.to_string(),
             db,
+            scope: PhantomData,
         })
     }
 }
 
-impl<'r, const ALLOW_QUERY: bool> AuthService<ALLOW_QUERY> {
+impl<'r, S: Scope, const ALLOW_QUERY: bool> AuthService<S, ALLOW_QUERY> {
     fn get_token(req: &'r Request<'_>) -> Option<&'r str> {
         if ALLOW_QUERY {
             let token = if let Some(token) = req.headers().get(AUTH_HEADER).next() {
@@ -122,24 +288,43 @@ impl<'r, const ALLOW_QUERY: bool> AuthService<ALLOW_QUERY> {
         }
     }
 
+    /// Accepts the token of the endpoint owner or, if set, the token of the
+    /// scope. An endpoint without owner token is only open for actions
+    /// without a token of their own, and has no owner once it has one. With
+    /// chosen owner and scoped tokens a wrong token costs two argon2
+    /// verifications.
     pub async fn check(&mut self, id: &str) -> Result<(), Status> {
-        if let Ok(auth) = sqlx::query_as::<_, Auth>("SELECT id, token FROM auth WHERE id = ?;")
+        self.check_as::<S>(id).await
+    }
+
+    /// Like [`Self::check`], for the scope `T` instead.
+    pub async fn check_as<T: Scope>(&mut self, id: &str) -> Result<(), Status> {
+        let query = format!(
+            "SELECT token_hash, {}, send_hash IS NOT NULL OR read_hash IS NOT NULL
+                FROM auth WHERE id = ?;",
+            T::COLUMN.unwrap_or("NULL")
+        );
+        match sqlx::query_as::<_, (String, Option<String>, bool)>(&query)
             .bind(id)
             .fetch_one(&mut **self.db)
             .await
         {
-            return if auth.token.is_empty() || auth.token.eq(&self.token) {
-                Ok(())
-            } else {
-                Err(Status::Unauthorized)
-            };
-        };
-        Err(Status::NotFound)
+            Ok((hash, scoped, scoped_tokens)) => {
+                authorize::<T>(hash, scoped, scoped_tokens, &self.token).await
+            }
+            Err(_) => Err(Status::NotFound),
+        }
     }
 
     pub async fn check_bool(&mut self, id: &str) -> bool {
         self.check(id).await.is_ok()
     }
+
+    pub async fn touch(&mut self, id: &str) {
+        if let Err(e) = touch(&mut self.db, id).await {
+            eprintln!("Could not renew {id}: {e}");
+        }
+    }
 }
 
 pub struct NewAuthService {
@@ -162,26 +347,135 @@ impl<'r> FromRequest<'r> for NewAuthService {
 
 impl NewAuthService {
     pub async fn save_random(self) -> Result<Auth, Status> {
-        self.save(Auth::rnd()).await
+        self.insert(Auth::rnd(), true).await
+    }
+
+    pub async fn save(self, auth: Auth) -> Result<Auth, Status> {
+        self.insert(auth, false).await
     }
 
-    pub async fn save(mut self, mut auth: Auth) -> Result<Auth, Status> {
+    async fn insert(mut self, mut auth: Auth, generated: bool) -> Result<Auth, Status> {
         if auth.id.len() < 8 {
             #[allow(unused_parens)]
             {
                 auth.id += &nanoid!((8 - auth.id.len()));
             }
         }
-        sqlx::query("INSERT OR FAIL INTO auth (id, token, ts) VALUES (?, ?, ?);")
-            .bind(&auth.id)
-            .bind(&auth.token)
-            .bind(custom_timestamp(*MY_EPOCH))
-            .execute(&mut **self.db)
-            .await
-            .map_err(|e| {
-                eprintln!("SQL: {e:?}");
-                Status::Unauthorized
-            })?;
+        auth.body_limit = auth.body_limit.map(|limit| limit.min(CONFIG.body_limit()));
+        let ttl = ttl(auth.ttl);
+        auth.ttl = Some(ttl as u64);
+        let tokens = (
+            auth.token.clone(),
+            auth.send_token.clone(),
+            auth.read_token.clone(),
+        );
+        // Like verifying, hashing chosen tokens with argon2 blocks for a while.
+        let (token_hash, send_hash, read_hash) = spawn_blocking(move || {
+            let hash = |token: &str| {
+                if generated {
+                    return Ok(hash_generated_token(token));
+                }
+                hash_token(token).map_err(|e| {
+                    eprintln!("Could not hash token: {e}");
+                    Status::InternalServerError
+                })
+            };
+            let (token, send_token, read_token) = tokens;
+            Ok::<_, Status>((
+                hash(&token)?,
+                send_token.as_deref().map(hash).transpose()?,
+                read_token.as_deref().map(hash).transpose()?,
+            ))
+        })
+        .await
+        .map_err(|e| {
+            eprintln!("Could not hash token: {e}");
+            Status::InternalServerError
+        })??;
+        let now = custom_timestamp(*MY_EPOCH);
+        sqlx::query(
+            "INSERT OR FAIL INTO auth
+                (id, token_hash, send_hash, read_hash, ts, ttl, expires, body_limit)
+                VALUES (?, ?, ?, ?, ?, ?, ?, ?);",
+        )
+        .bind(&auth.id)
+        .bind(token_hash)
+        .bind(send_hash)
+        .bind(read_hash)
+        .bind(now)
+        .bind(ttl)
+        .bind(now + ttl)
+        .bind(auth.body_limit.map(|limit| limit as i64))
+        .execute(&mut **self.db)
+        .await
+        .map_err(|e| {
+            eprintln!("SQL: {e:?}");
+            Status::Unauthorized
+        })?;
         Ok(auth)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[rocket::async_test]
+    async fn scoped_tokens_without_owner_token() {
+        let send = hash_token("sendsecret").unwrap();
+        let read = hash_token("readsecret").unwrap();
+
+        assert_eq!(
+            authorize::<Owner>(String::new(), None, true, "").await,
+            Err(Status::Unauthorized)
+        );
+        assert_eq!(
+            authorize::<Owner>(String::new(), None, true, "readsecret").await,
+            Err(Status::Unauthorized)
+        );
+        assert_eq!(
+            authorize::<Subscriber>(String::new(), Some(read.clone()), true, "readsecret").await,
+            Ok(())
+        );
+        assert_eq!(
+            authorize::<Subscriber>(String::new(), Some(read), true, "").await,
+            Err(Status::Unauthorized)
+        );
+        assert_eq!(
+            authorize::<Sender>(String::new(), Some(send), true, "sendsecret").await,
+            Ok(())
+        );
+        // A scope without a token of its own stays open.
+        assert_eq!(
+            authorize::<Sender>(String::new(), None, true, "").await,
+            Ok(())
+        );
+    }
+
+    #[rocket::async_test]
+    async fn owner_token() {
+        let owner = hash_token("owner").unwrap();
+        let read = hash_token("reader").unwrap();
+
+        assert_eq!(
+            authorize::<Owner>(String::new(), None, false, "").await,
+            Ok(())
+        );
+        assert_eq!(
+            authorize::<Owner>(owner.clone(), None, true, "owner").await,
+            Ok(())
+        );
+        assert_eq!(
+            authorize::<Owner>(owner.clone(), None, true, "reader").await,
+            Err(Status::Unauthorized)
+        );
+        assert_eq!(
+            authorize::<Subscriber>(owner.clone(), Some(read), true, "owner").await,
+            Ok(())
+        );
+        assert_eq!(
+            authorize::<Sender>(owner, None, true, "").await,
+            Err(Status::Unauthorized)
+        );
+    }
+}
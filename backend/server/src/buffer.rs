@@ -0,0 +1,61 @@
+use std::{
+    collections::VecDeque,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use dashmap::DashMap;
+
+use crate::{request_data::RequestData, CONFIG};
+
+struct Buffered {
+    received: Instant,
+    request: RequestData,
+}
+
+/// Requests that arrived while no subscriber was connected, kept per endpoint
+/// until the next websocket connects.
+#[derive(Clone, Default)]
+pub struct Buffer(Arc<DashMap<String, VecDeque<Buffered>>>);
+
+impl Buffer {
+    pub fn push(&self, id: &str, request: RequestData) {
+        let max_count = CONFIG.buffer_max_count();
+        if max_count == 0 {
+            return;
+        }
+        let max_age = Duration::from_secs(CONFIG.buffer_max_age());
+        let mut queue = self.0.entry(id.to_owned()).or_default();
+        while queue
+            .front()
+            .is_some_and(|buffered| buffered.received.elapsed() > max_age)
+        {
+            queue.pop_front();
+        }
+        while queue.len() >= max_count {
+            queue.pop_front();
+        }
+        queue.push_back(Buffered {
+            received: Instant::now(),
+            request,
+        });
+    }
+
+    /// Removes and returns every buffered request of the endpoint that is
+    /// younger than the configured maximum age, oldest first.
+    pub fn drain(&self, id: &str) -> Vec<RequestData> {
+        let max_age = Duration::from_secs(CONFIG.buffer_max_age());
+        match self.0.remove(id) {
+            Some((_, queue)) => queue
+                .into_iter()
+                .filter(|buffered| buffered.received.elapsed() <= max_age)
+                .map(|buffered| buffered.request)
+                .collect(),
+            None => vec![],
+        }
+    }
+
+    pub fn clear(&self, id: &str) {
+        self.0.remove(id);
+    }
+}
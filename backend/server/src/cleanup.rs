@@ -1,18 +1,27 @@
 use std::fs::File;
 use std::io::prelude::*;
+use std::time::Duration;
 
-use futures::SinkExt as _;
 use rocket::{
+    fairing::AdHoc,
     http::Status,
     request::{FromRequest, Outcome},
+    tokio::{
+        self,
+        time::{self, MissedTickBehavior},
+    },
     Request, State,
 };
-use rocket_db_pools::{sqlx, Connection};
+use rocket_db_pools::{
+    sqlx::{self, SqliteConnection},
+    Connection, Database,
+};
 use shared::custom_timestamp;
 
 use crate::{
-    auth::{Auth, AuthDb},
-    ThingMap, WsMessage, AUTH_HEADER, MY_EPOCH,
+    auth::{self, AuthDb},
+    buffer::Buffer,
+    endpoints, history, metrics, ThingMap, WsMessage, AUTH_HEADER, MY_EPOCH,
 };
 
 use super::{CLEANUP_TOKEN, CONFIG};
@@ -23,6 +32,7 @@ pub(crate) fn init() {
         .expect("Could not write token");
 }
 
+/// The admin token, in `X-Auth` or as bearer token in `Authorization`.
 pub struct ConfigurationAuth();
 
 #[rocket::async_trait]
@@ -30,7 +40,13 @@ impl<'r> FromRequest<'r> for ConfigurationAuth {
     type Error = ();
 
     async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error> {
-        let token = if let Some(token) = req.headers().get(AUTH_HEADER).next() {
+        let token = if let Some(token) = req.headers().get(AUTH_HEADER).next().or_else(|| {
+            req.headers()
+                .get("Authorization")
+                .filter_map(|value| value.split_once(' '))
+                .find(|(scheme, _)| scheme.eq_ignore_ascii_case("bearer"))
+                .map(|(_, token)| token.trim())
+        }) {
             token
         } else {
             return Outcome::Forward(Status::Unauthorized);
@@ -47,42 +63,99 @@ pub async fn cleanup_tokens(
     _ca: ConfigurationAuth,
     mut db: Connection<AuthDb>,
     map: &State<ThingMap>,
+    buffer: &State<Buffer>,
 ) -> Status {
-    let ts = custom_timestamp(*MY_EPOCH) - CONFIG.max_age();
-    match sqlx::query_as::<_, Auth>("SELECT id, token FROM auth WHERE ts < ?;")
-        .bind(ts)
-        .fetch_all(&mut **db)
-        .await
-    {
-        Ok(res) => {
-            println!("{} Tokens expired", res.len());
-            for auth in res {
-                if let Some((_, senders)) = map.remove(auth.id()) {
-                    for sender in senders {
-                        if !sender.is_closed() {
-                            if let Err(e) = sender.clone().send(WsMessage::TokenExpired).await {
-                                eprintln!(
-                                    "Send Error (closing channel due to token expiration): {}",
-                                    e
-                                );
-                                sender.clone().close_channel();
-                            };
-                        }
-                    }
-                }
-            }
-            if let Err(e) = sqlx::query("DELETE FROM auth WHERE ts < ?;")
-                .bind(ts)
-                .execute(&mut **db)
-                .await
-            {
-                eprintln!("Could not delete expired tokens from DB: {e}");
-            }
-            Status::Accepted
-        }
+    match expire_tokens(&mut db, map, buffer).await {
+        Ok(_) => Status::Accepted,
         Err(e) => {
             eprintln!("Could not clear tokens! {e}");
             Status::InternalServerError
         }
     }
 }
+
+/// Runs [`expire_tokens`] every `cleanup_interval` seconds until shutdown. An
+/// interval of 0 disables the timer, leaving only the admin route.
+pub fn scheduler() -> AdHoc {
+    AdHoc::on_liftoff("Token Cleanup", |rocket| {
+        Box::pin(async move {
+            if CONFIG.cleanup_interval() == 0 {
+                return;
+            }
+            let (Some(db), Some(map), Some(buffer)) = (
+                AuthDb::fetch(rocket),
+                rocket.state::<ThingMap>(),
+                rocket.state::<Buffer>(),
+            ) else {
+                eprintln!("Could not start token cleanup");
+                return;
+            };
+            let pool = (**db).clone();
+            let map = map.clone();
+            let buffer = buffer.clone();
+            let mut shutdown = rocket.shutdown();
+
+            tokio::spawn(async move {
+                let mut interval = time::interval(Duration::from_secs(CONFIG.cleanup_interval()));
+                interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+                loop {
+                    tokio::select! {
+                        _ = interval.tick() => {},
+                        _ = &mut shutdown => break,
+                    }
+                    let result = match pool.acquire().await {
+                        Ok(mut conn) => expire_tokens(&mut conn, &map, &buffer).await,
+                        Err(e) => Err(e),
+                    };
+                    if let Err(e) = result {
+                        eprintln!("Could not clear tokens! {e}");
+                    }
+                }
+            });
+        })
+    })
+}
+
+/// Removes every endpoint past its expiry together with its history and
+/// buffered requests, closing the websockets subscribed to it. Endpoints with
+/// a connected subscriber count as active and are renewed instead.
+pub async fn expire_tokens(
+    db: &mut SqliteConnection,
+    map: &ThingMap,
+    buffer: &Buffer,
+) -> sqlx::Result<usize> {
+    let subscribed: Vec<String> = map
+        .iter()
+        .filter(|entry| entry.value().iter().any(|sender| !sender.is_closed()))
+        .map(|entry| entry.key().clone())
+        .collect();
+    for id in &subscribed {
+        auth::touch(&mut *db, id).await?;
+    }
+
+    let ts = custom_timestamp(*MY_EPOCH);
+    let res = sqlx::query_scalar::<_, String>("SELECT id FROM auth WHERE expires < ?;")
+        .bind(ts)
+        .fetch_all(&mut *db)
+        .await?;
+
+    if !res.is_empty() {
+        println!("{} Tokens expired", res.len());
+        metrics::EXPIRED.inc_by(res.len() as u64);
+    }
+    for id in &res {
+        buffer.clear(id);
+        endpoints::close_subscribers(map, id, WsMessage::TokenExpired).await;
+    }
+    if let Err(e) = history::clear_expired(&mut *db, ts).await {
+        eprintln!("Could not delete history of expired tokens from DB: {e}");
+    }
+    if let Err(e) = sqlx::query("DELETE FROM auth WHERE expires < ?;")
+        .bind(ts)
+        .execute(&mut *db)
+        .await
+    {
+        eprintln!("Could not delete expired tokens from DB: {e}");
+    }
+    Ok(res.len())
+}
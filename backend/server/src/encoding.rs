@@ -0,0 +1,146 @@
+use std::io::{self, Read};
+
+use flate2::read::{DeflateDecoder, GzDecoder, ZlibDecoder};
+
+/// Undoes the `Content-Encoding` of a body, decoding at most `limit` bytes.
+/// Returns `None` if there is nothing to decode.
+pub fn decode(content_encoding: &str, bytes: &[u8], limit: u64) -> io::Result<Option<Vec<u8>>> {
+    let encodings = encodings(content_encoding);
+    if encodings.is_empty() {
+        return Ok(None);
+    }
+    if let Some(unknown) = encodings
+        .iter()
+        .find(|encoding| !matches!(encoding.as_str(), "gzip" | "x-gzip" | "deflate" | "br"))
+    {
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            format!("unknown encoding {unknown}"),
+        ));
+    }
+
+    // Encodings are listed in the order they were applied.
+    let mut decoded = bytes.to_vec();
+    for encoding in encodings.iter().rev() {
+        decoded = match encoding.as_str() {
+            "gzip" | "x-gzip" => read(GzDecoder::new(&decoded[..]), limit)?,
+            // Some senders use raw deflate instead of the zlib format.
+            "deflate" => read(ZlibDecoder::new(&decoded[..]), limit)
+                .or_else(|_| read(DeflateDecoder::new(&decoded[..]), limit))?,
+            _ => read(brotli::Decompressor::new(&decoded[..], 4096), limit)?,
+        };
+    }
+    Ok(Some(decoded))
+}
+
+/// Whether the `Content-Encoding` changes the body at all.
+pub fn is_encoded(content_encoding: &str) -> bool {
+    !encodings(content_encoding).is_empty()
+}
+
+fn encodings(content_encoding: &str) -> Vec<String> {
+    content_encoding
+        .split(',')
+        .map(|encoding| encoding.trim().to_ascii_lowercase())
+        .filter(|encoding| !encoding.is_empty() && encoding != "identity")
+        .collect()
+}
+
+fn read(reader: impl Read, limit: u64) -> io::Result<Vec<u8>> {
+    let mut decoded = Vec::new();
+    reader.take(limit + 1).read_to_end(&mut decoded)?;
+    if decoded.len() as u64 > limit {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("decoded body exceeds the limit of {limit} bytes"),
+        ));
+    }
+    Ok(decoded)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use flate2::{
+        write::{DeflateEncoder, GzEncoder, ZlibEncoder},
+        Compression,
+    };
+
+    use super::*;
+
+    const BODY: &[u8] = b"{\"hello\":\"world\"}";
+
+    fn gzip(bytes: &[u8]) -> Vec<u8> {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(bytes).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    fn brotli(bytes: &[u8]) -> Vec<u8> {
+        let mut out = Vec::new();
+        {
+            let mut encoder = brotli::CompressorWriter::new(&mut out, 4096, 5, 22);
+            encoder.write_all(bytes).unwrap();
+        }
+        out
+    }
+
+    #[test]
+    fn decodes_gzip() {
+        for name in ["gzip", "x-gzip", " GZip "] {
+            assert_eq!(decode(name, &gzip(BODY), 100).unwrap().unwrap(), BODY);
+        }
+    }
+
+    #[test]
+    fn decodes_zlib_and_raw_deflate() {
+        let mut zlib = ZlibEncoder::new(Vec::new(), Compression::default());
+        zlib.write_all(BODY).unwrap();
+        let mut raw = DeflateEncoder::new(Vec::new(), Compression::default());
+        raw.write_all(BODY).unwrap();
+        for encoded in [zlib.finish().unwrap(), raw.finish().unwrap()] {
+            assert_eq!(decode("deflate", &encoded, 100).unwrap().unwrap(), BODY);
+        }
+    }
+
+    #[test]
+    fn decodes_brotli() {
+        assert_eq!(decode("br", &brotli(BODY), 100).unwrap().unwrap(), BODY);
+    }
+
+    #[test]
+    fn decodes_stacked_encodings_in_reverse() {
+        let encoded = brotli(&gzip(BODY));
+        assert_eq!(decode("gzip, br", &encoded, 100).unwrap().unwrap(), BODY);
+    }
+
+    #[test]
+    fn stops_at_the_limit() {
+        let bomb = gzip(&vec![0; 1_000_000]);
+        assert!(bomb.len() < 10_000);
+        let e = decode("gzip", &bomb, 64 * 1024).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(
+            decode("gzip", &bomb, 1_000_000).unwrap().unwrap().len(),
+            1_000_000
+        );
+    }
+
+    #[test]
+    fn identity_is_not_decoded() {
+        assert_eq!(decode("identity", BODY, 100).unwrap(), None);
+        assert_eq!(decode("", BODY, 100).unwrap(), None);
+        assert!(!is_encoded(" identity "));
+        assert!(is_encoded("identity, gzip"));
+    }
+
+    #[test]
+    fn rejects_unknown_encodings() {
+        for name in ["compress", "gzip, zstd"] {
+            let e = decode(name, &gzip(BODY), 100).unwrap_err();
+            assert_eq!(e.kind(), io::ErrorKind::Unsupported);
+        }
+        assert!(decode("gzip", BODY, 100).is_err());
+    }
+}
@@ -0,0 +1,273 @@
+use futures::SinkExt as _;
+use nanoid::nanoid;
+use rocket::{http::Status, serde::json::Json, State};
+use rocket_db_pools::{
+    sqlx::{self, SqliteConnection},
+    Connection,
+};
+use serde::Serialize;
+
+use crate::{
+    auth::{self, AuthDb, AuthService, Credentials, Subscriber},
+    buffer::Buffer,
+    history, ThingMap, WsMessage,
+};
+
+#[derive(Clone, Copy, Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum TokenScope {
+    Owner,
+    Send,
+    Read,
+}
+
+impl TokenScope {
+    fn parse(scope: Option<&str>) -> Option<Self> {
+        match scope {
+            None | Some("owner") => Some(TokenScope::Owner),
+            Some("send") => Some(TokenScope::Send),
+            Some("read") => Some(TokenScope::Read),
+            Some(_) => None,
+        }
+    }
+
+    fn column(self) -> &'static str {
+        match self {
+            TokenScope::Owner => "token_hash",
+            TokenScope::Send => "send_hash",
+            TokenScope::Read => "read_hash",
+        }
+    }
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Credential {
+    id: String,
+    scope: TokenScope,
+    token: String,
+}
+
+/// Removes the subscribers of an endpoint, sending each of them `message`
+/// to close its websocket.
+pub async fn close_subscribers(map: &ThingMap, id: &str, message: WsMessage) {
+    if let Some((_, senders)) = map.remove(id) {
+        for sender in senders {
+            if !sender.is_closed() {
+                if let Err(e) = sender.clone().send(message.clone()).await {
+                    eprintln!("Send Error (closing channel): {}", e);
+                    sender.clone().close_channel();
+                };
+            }
+        }
+    }
+}
+
+/// Replaces a token of the endpoint with a new random one, the owner token
+/// unless `scope` is `send` or `read`. Subscribers that may have connected
+/// with the old token are disconnected. Endpoints without owner token cannot
+/// rotate.
+#[post("/endpoints/<id>/rotate?<scope>")]
+pub async fn rotate(
+    id: &str,
+    scope: Option<&str>,
+    mut auth: AuthService<Credentials>,
+    mut db: Connection<AuthDb>,
+    map: &State<ThingMap>,
+) -> Result<Json<Credential>, Status> {
+    auth.check(id).await?;
+    let scope = TokenScope::parse(scope).ok_or(Status::UnprocessableEntity)?;
+    let token = nanoid!();
+    let hash = auth::hash_generated_token(&token);
+
+    let query = format!("UPDATE auth SET {} = ? WHERE id = ?;", scope.column());
+    if let Err(e) = sqlx::query(&query)
+        .bind(hash)
+        .bind(id)
+        .execute(&mut **db)
+        .await
+    {
+        eprintln!("Could not rotate token: {e}");
+        return Err(Status::InternalServerError);
+    }
+
+    if !matches!(scope, TokenScope::Send) {
+        close_subscribers(map, id, WsMessage::TokenRevoked).await;
+    }
+    Ok(Json(Credential {
+        id: id.to_owned(),
+        scope,
+        token,
+    }))
+}
+
+/// Deletes the endpoint with its history and buffered requests. Endpoints
+/// without owner token only expire.
+#[delete("/endpoints/<id>")]
+pub async fn delete(
+    id: &str,
+    mut auth: AuthService<Credentials>,
+    mut db: Connection<AuthDb>,
+    map: &State<ThingMap>,
+    buffer: &State<Buffer>,
+) -> Status {
+    if let Err(s) = auth.check(id).await {
+        return s;
+    }
+    close_subscribers(map, id, WsMessage::TokenRevoked).await;
+    buffer.clear(id);
+    if let Err(e) = history::clear(&mut db, id).await {
+        eprintln!("Could not delete history of {id}: {e}");
+    }
+    match sqlx::query("DELETE FROM auth WHERE id = ?;")
+        .bind(id)
+        .execute(&mut **db)
+        .await
+    {
+        Ok(_) => Status::NoContent,
+        Err(e) => {
+            eprintln!("Could not delete endpoint {id}: {e}");
+            Status::InternalServerError
+        }
+    }
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Expiry {
+    id: String,
+    /// Seconds until the endpoint expires unless it is used or renewed again.
+    ttl: i64,
+}
+
+/// Restarts the expiry countdown of the endpoint, optionally with a new
+/// time-to-live in seconds (capped by `max_age`).
+#[post("/endpoints/<id>/renew?<ttl>")]
+pub async fn renew(
+    id: &str,
+    ttl: Option<u64>,
+    mut auth: AuthService<Subscriber>,
+    mut db: Connection<AuthDb>,
+) -> Result<Json<Expiry>, Status> {
+    auth.check(id).await?;
+    match extend(&mut db, id, ttl).await {
+        Ok(ttl) => Ok(Json(Expiry {
+            id: id.to_owned(),
+            ttl,
+        })),
+        Err(e) => {
+            eprintln!("Could not renew {id}: {e}");
+            Err(Status::InternalServerError)
+        }
+    }
+}
+
+async fn extend(db: &mut SqliteConnection, id: &str, ttl: Option<u64>) -> sqlx::Result<i64> {
+    if let Some(ttl) = ttl {
+        sqlx::query("UPDATE auth SET ttl = ? WHERE id = ?;")
+            .bind(auth::ttl(Some(ttl)))
+            .bind(id)
+            .execute(&mut *db)
+            .await?;
+    }
+    auth::touch(&mut *db, id).await?;
+    sqlx::query_scalar("SELECT ttl FROM auth WHERE id = ?;")
+        .bind(id)
+        .fetch_one(db)
+        .await
+}
+
+#[cfg(test)]
+mod tests {
+    use rocket::{
+        http::{Header, Status},
+        local::asynchronous::Client,
+    };
+    use rocket_db_pools::Database;
+
+    use super::*;
+    use crate::{CONFIG_PATH, MY_EPOCH};
+
+    async fn client() -> Client {
+        let dir = std::env::temp_dir().join(format!("req-endpoints-{}", nanoid!()));
+        std::fs::create_dir_all(&dir).unwrap();
+        CONFIG_PATH.get_or_init(|| {
+            let config = dir.join("config.ini");
+            std::fs::write(
+                &config,
+                "ui_path=.\nmy_epoch=2021-11-22 0:0:0\ncleanup_interval=5\nmax_age=3600\n\
+                 auth_db=./auth.sqlite\nsecret_path=./.token.req\n",
+            )
+            .unwrap();
+            config.to_string_lossy().into_owned()
+        });
+        let figment = rocket::Config::figment()
+            .merge(("log_level", "off"))
+            .merge(("databases.auth.url", dir.join("auth.sqlite")));
+        let rocket = auth::attach_db(rocket::custom(figment))
+            .manage(ThingMap::default())
+            .manage(Buffer::default())
+            .mount("/", routes![rotate, delete]);
+        Client::untracked(rocket).await.unwrap()
+    }
+
+    async fn register(client: &Client, id: &str, token: &str, read_token: Option<&str>) {
+        let db = AuthDb::fetch(client.rocket()).unwrap();
+        let now = shared::custom_timestamp(*MY_EPOCH);
+        sqlx::query(
+            "INSERT INTO auth (id, token_hash, read_hash, ts, ttl, expires) VALUES (?, ?, ?, ?, 3600, ?);",
+        )
+        .bind(id)
+        .bind(auth::hash_token(token).unwrap())
+        .bind(read_token.map(|token| auth::hash_token(token).unwrap()))
+        .bind(now)
+        .bind(now + 3600)
+        .execute(&**db)
+        .await
+        .unwrap();
+    }
+
+    #[rocket::async_test]
+    async fn anonymous_callers_cannot_rotate_or_delete() {
+        let client = client().await;
+        register(&client, "scopedep1", "", Some("readsecret")).await;
+        register(&client, "openendpoint", "", None).await;
+
+        for id in ["scopedep1", "openendpoint"] {
+            for scope in ["owner", "send", "read"] {
+                let response = client
+                    .post(format!("/endpoints/{id}/rotate?scope={scope}"))
+                    .dispatch()
+                    .await;
+                assert_eq!(response.status(), Status::Unauthorized, "{id} {scope}");
+            }
+            let response = client.delete(format!("/endpoints/{id}")).dispatch().await;
+            assert_eq!(response.status(), Status::Unauthorized, "{id}");
+        }
+        let response = client
+            .post("/endpoints/scopedep1/rotate?scope=read")
+            .header(Header::new("X-Auth", "readsecret"))
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Unauthorized);
+    }
+
+    #[rocket::async_test]
+    async fn owners_rotate_and_delete() {
+        let client = client().await;
+        register(&client, "ownedendpoint", "owner", None).await;
+
+        let response = client
+            .post("/endpoints/ownedendpoint/rotate?scope=read")
+            .header(Header::new("X-Auth", "owner"))
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Ok);
+        let response = client
+            .delete("/endpoints/ownedendpoint")
+            .header(Header::new("X-Auth", "owner"))
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::NoContent);
+    }
+}
@@ -0,0 +1,107 @@
+use rocket::{
+    futures::StreamExt,
+    http::Status,
+    request::{FromRequest, Outcome},
+    response::stream::{Event, EventStream},
+    Request, State,
+};
+use rocket_db_pools::Connection;
+
+use crate::{
+    auth::{AuthDb, AuthService, Subscriber},
+    buffer::Buffer,
+    history,
+    request_data::RequestData,
+    subscribe, ThingMap, WsMessage,
+};
+
+/// The `Last-Event-ID` header an `EventSource` sends when it reconnects.
+pub struct LastEventId(Option<i64>);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for LastEventId {
+    type Error = ();
+
+    async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        Outcome::Success(LastEventId(
+            req.headers()
+                .get_one("Last-Event-ID")
+                .and_then(|id| id.trim().parse().ok()),
+        ))
+    }
+}
+
+fn request_event(req: &RequestData) -> Event {
+    let event =
+        Event::data(serde_json::to_string(req).unwrap_or("ERROR".to_string())).event("request");
+    match req.seq() {
+        Some(seq) => event.id(seq.to_string()),
+        None => event,
+    }
+}
+
+/// Server-sent events alternative to the websocket on `/connect/<id>`. Each
+/// captured request is a `request` event with its history position as id, so
+/// a reconnecting client gets the requests it missed. Expiry, revocation and
+/// shutdown are sent as `expired`, `revoked` and `shutdown` events, after which
+/// the stream ends.
+#[get("/events/<id>")]
+pub async fn events<'r>(
+    id: &'r str,
+    mut auth: AuthService<Subscriber, true>,
+    mut db: Connection<AuthDb>,
+    last_event_id: LastEventId,
+    map: &'r State<ThingMap>,
+    buffer: &'r State<Buffer>,
+) -> Result<EventStream![Event + 'r], Status> {
+    auth.check(id).await?;
+    auth.touch(id).await;
+    let mut receiver = subscribe(map, id);
+
+    let mut last = last_event_id.0;
+    let mut backlog = vec![];
+    if let Some(seq) = last {
+        let missed = history::since(&mut db, id, seq).await.map_err(|e| {
+            eprintln!("Could not read history: {e}");
+            Status::InternalServerError
+        })?;
+        for (seq, data) in missed {
+            backlog.push(Event::data(data).event("request").id(seq.to_string()));
+            last = Some(seq);
+        }
+    }
+    let seen = move |req: &RequestData| req.seq().zip(last).is_some_and(|(seq, last)| seq <= last);
+    for req in buffer.drain(id) {
+        if !seen(&req) {
+            backlog.push(request_event(&req));
+        }
+    }
+
+    Ok(EventStream! {
+        for event in backlog {
+            yield event;
+        }
+        while let Some(message) = receiver.next().await {
+            match message {
+                WsMessage::Request(req) => {
+                    if !seen(&req) {
+                        yield request_event(&req);
+                    }
+                }
+                WsMessage::TokenExpired => {
+                    yield Event::data(id.to_owned()).event("expired");
+                    break;
+                }
+                WsMessage::TokenRevoked => {
+                    yield Event::data(id.to_owned()).event("revoked");
+                    break;
+                }
+                WsMessage::ServerShutdown => {
+                    yield Event::data(id.to_owned()).event("shutdown");
+                    break;
+                }
+                WsMessage::Shutdown => break,
+            }
+        }
+    })
+}
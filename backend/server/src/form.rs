@@ -0,0 +1,60 @@
+use std::convert::Infallible;
+
+use base64::{engine::general_purpose::STANDARD as Base64, Engine as _};
+use multimap::MultiMap;
+use rocket::{futures::stream, http::RawStr};
+use serde::Serialize;
+
+/// A part of a `multipart/form-data` body.
+#[derive(Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Part {
+    name: Option<String>,
+    filename: Option<String>,
+    content_type: Option<String>,
+    size: u64,
+    /// The content if it is valid UTF-8.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    raw: Option<String>,
+    base64: String,
+}
+
+/// Decodes an `application/x-www-form-urlencoded` body, lossily where it is
+/// not valid UTF-8.
+pub fn fields(bytes: &[u8]) -> MultiMap<String, String> {
+    let body = String::from_utf8_lossy(bytes);
+    let mut fields = MultiMap::new();
+    for pair in body.split('&').filter(|pair| !pair.is_empty()) {
+        let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+        fields.insert(
+            RawStr::new(name).url_decode_lossy().into_owned(),
+            RawStr::new(value).url_decode_lossy().into_owned(),
+        );
+    }
+    fields
+}
+
+/// Splits a `multipart/form-data` body into its parts.
+pub async fn parts(bytes: &[u8], boundary: &str) -> multer::Result<Vec<Part>> {
+    let body = bytes.to_vec();
+    let mut multipart = multer::Multipart::new(
+        stream::once(async move { Ok::<_, Infallible>(body) }),
+        boundary,
+    );
+    let mut parts = vec![];
+    while let Some(field) = multipart.next_field().await? {
+        let name = field.name().map(str::to_owned);
+        let filename = field.file_name().map(str::to_owned);
+        let content_type = field.content_type().map(ToString::to_string);
+        let content = field.bytes().await?;
+        parts.push(Part {
+            name,
+            filename,
+            content_type,
+            size: content.len() as u64,
+            raw: std::str::from_utf8(&content).ok().map(str::to_owned),
+            base64: Base64.encode(&content),
+        });
+    }
+    Ok(parts)
+}
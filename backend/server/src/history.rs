@@ -0,0 +1,313 @@
+use rocket::{http::Status, serde::json::Json};
+use rocket_db_pools::{
+    sqlx::{self, sqlite::SqliteRow, FromRow, Row, SqliteConnection},
+    Connection,
+};
+use serde::Serialize;
+use serde_json::Value;
+use shared::{
+    custom_timestamp,
+    har::{self, Har},
+    repro::{self, Format},
+    CapturedRequest,
+};
+
+use crate::{
+    auth::{AuthDb, AuthService, Subscriber},
+    request_data::RequestData,
+    spool, CONFIG, MY_EPOCH,
+};
+
+const DEFAULT_PAGE_SIZE: u32 = 20;
+pub const MAX_PAGE_SIZE: u32 = 100;
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct HistoryEntry {
+    seq: i64,
+    data: Value,
+}
+
+impl HistoryEntry {
+    pub fn new(seq: i64, data: Value) -> Self {
+        Self { seq, data }
+    }
+}
+
+impl FromRow<'_, SqliteRow> for HistoryEntry {
+    fn from_row(row: &SqliteRow) -> sqlx::Result<Self> {
+        let data: String = row.try_get("data")?;
+        Ok(Self {
+            seq: row.try_get("seq")?,
+            data: serde_json::from_str(&data).map_err(|e| sqlx::Error::Decode(Box::new(e)))?,
+        })
+    }
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct HistoryPage {
+    items: Vec<HistoryEntry>,
+    next: Option<i64>,
+}
+
+/// Persists a captured request and drops the oldest entries of the endpoint
+/// beyond the configured history size. Returns the sequence number of the entry.
+pub async fn store(db: &mut SqliteConnection, id: &str, input: &RequestData) -> sqlx::Result<i64> {
+    let seq = match insert(db, id, input).await {
+        Ok(seq) => seq,
+        Err(e) => {
+            // Without an entry the spooled body can never be downloaded.
+            spool::remove(&[input.id().to_string()]).await;
+            return Err(e);
+        }
+    };
+
+    let removed = sqlx::query_scalar::<_, String>(
+        "DELETE FROM history WHERE endpoint = ? AND seq <= (
+            SELECT seq FROM history WHERE endpoint = ? ORDER BY seq DESC LIMIT 1 OFFSET ?)
+            RETURNING request_id;",
+    )
+    .bind(id)
+    .bind(id)
+    .bind(CONFIG.history_size())
+    .fetch_all(&mut *db)
+    .await?;
+    spool::remove(&removed).await;
+    if !removed.is_empty() {
+        sqlx::query(
+            "DELETE FROM replays WHERE endpoint = ?
+                AND request_id NOT IN (SELECT request_id FROM history WHERE endpoint = ?);",
+        )
+        .bind(id)
+        .bind(id)
+        .execute(&mut *db)
+        .await?;
+    }
+
+    Ok(seq)
+}
+
+async fn insert(db: &mut SqliteConnection, id: &str, input: &RequestData) -> sqlx::Result<i64> {
+    let data = serde_json::to_string(input).map_err(|e| sqlx::Error::Protocol(e.to_string()))?;
+    Ok(sqlx::query(
+        "INSERT INTO history (endpoint, request_id, method, path, data, ts) VALUES (?, ?, ?, ?, ?, ?);",
+    )
+    .bind(id)
+    .bind(input.id().to_string())
+    .bind(input.method().as_str())
+    .bind(input.path())
+    .bind(data)
+    .bind(custom_timestamp(*MY_EPOCH))
+    .execute(db)
+    .await?
+    .last_insert_rowid())
+}
+
+/// Removes the history of every endpoint that expired before `ts`.
+pub async fn clear_expired(db: &mut SqliteConnection, ts: i64) -> sqlx::Result<()> {
+    let removed = sqlx::query_scalar::<_, String>(
+        "DELETE FROM history WHERE endpoint IN (SELECT id FROM auth WHERE expires < ?)
+            RETURNING request_id;",
+    )
+    .bind(ts)
+    .fetch_all(&mut *db)
+    .await?;
+    spool::remove(&removed).await;
+    sqlx::query("DELETE FROM replays WHERE endpoint IN (SELECT id FROM auth WHERE expires < ?);")
+        .bind(ts)
+        .execute(db)
+        .await?;
+    Ok(())
+}
+
+/// Removes the whole history of an endpoint.
+pub async fn clear(db: &mut SqliteConnection, id: &str) -> sqlx::Result<()> {
+    let removed = sqlx::query_scalar::<_, String>(
+        "DELETE FROM history WHERE endpoint = ? RETURNING request_id;",
+    )
+    .bind(id)
+    .fetch_all(&mut *db)
+    .await?;
+    spool::remove(&removed).await;
+    sqlx::query("DELETE FROM replays WHERE endpoint = ?;")
+        .bind(id)
+        .execute(db)
+        .await?;
+    Ok(())
+}
+
+fn page_size(limit: Option<u32>) -> u32 {
+    limit.unwrap_or(DEFAULT_PAGE_SIZE).clamp(1, MAX_PAGE_SIZE)
+}
+
+/// Reads a page of the history of an endpoint, newest first.
+async fn entries(
+    db: &mut SqliteConnection,
+    id: &str,
+    limit: u32,
+    before: Option<i64>,
+    path: Option<&str>,
+) -> Result<Vec<HistoryEntry>, Status> {
+    let path = path
+        .map(|p| p.trim_end_matches('/'))
+        .filter(|p| !p.is_empty());
+    sqlx::query_as::<_, HistoryEntry>(
+        "SELECT seq, data FROM history WHERE endpoint = ? AND seq < ?
+            AND (? IS NULL OR path = ? OR substr(path, 1, length(?) + 1) = ? || '/')
+            ORDER BY seq DESC LIMIT ?;",
+    )
+    .bind(id)
+    .bind(before.unwrap_or(i64::MAX))
+    .bind(path)
+    .bind(path)
+    .bind(path)
+    .bind(path)
+    .bind(limit)
+    .fetch_all(db)
+    .await
+    .map_err(|e| {
+        eprintln!("Could not read history: {e}");
+        Status::InternalServerError
+    })
+}
+
+/// Lists the captured requests of an endpoint, newest first. Pass the `next`
+/// value of a page as `before` to fetch the following page. `path` restricts
+/// the list to requests sent to that sub-path or below.
+#[get("/history/<id>?<limit>&<before>&<path>")]
+pub async fn history(
+    id: &str,
+    mut auth: AuthService<Subscriber, true>,
+    mut db: Connection<AuthDb>,
+    limit: Option<u32>,
+    before: Option<i64>,
+    path: Option<&str>,
+) -> Result<Json<HistoryPage>, Status> {
+    auth.check(id).await?;
+
+    let limit = page_size(limit);
+    let items = entries(&mut db, id, limit, before, path).await?;
+    let next = if items.len() == limit as usize {
+        items.last().map(|entry| entry.seq)
+    } else {
+        None
+    };
+    Ok(Json(HistoryPage { items, next }))
+}
+
+fn captured(entry: HistoryEntry) -> Option<CapturedRequest> {
+    serde_json::from_value(entry.data)
+        .map_err(|e| eprintln!("Could not read history entry {}: {e}", entry.seq))
+        .ok()
+}
+
+/// Exports a page of the history as HAR, with the same parameters as
+/// [`history`]. Spooled bodies are only referenced, not included.
+#[get("/history/<id>/har?<limit>&<before>&<path>")]
+pub async fn export_har(
+    id: &str,
+    mut auth: AuthService<Subscriber, true>,
+    mut db: Connection<AuthDb>,
+    limit: Option<u32>,
+    before: Option<i64>,
+    path: Option<&str>,
+) -> Result<Json<Har>, Status> {
+    auth.check(id).await?;
+
+    let requests: Vec<_> = entries(&mut db, id, page_size(limit), before, path)
+        .await?
+        .into_iter()
+        .filter_map(captured)
+        .collect();
+    Ok(Json(har::to_har(&requests)))
+}
+
+/// Exports a single captured request as HAR, including a spooled body.
+#[get("/history/<id>/<request_id>/har")]
+pub async fn export_request_har(
+    id: &str,
+    request_id: &str,
+    mut auth: AuthService<Subscriber, true>,
+    mut db: Connection<AuthDb>,
+) -> Result<Json<Har>, Status> {
+    auth.check(id).await?;
+
+    let request = load(&mut db, id, request_id).await?;
+    Ok(Json(har::to_har(&[request])))
+}
+
+/// Renders a command or code snippet reproducing a captured request. `format`
+/// is one of `curl` (default), `httpie` or `reqwest`.
+#[get("/history/<id>/<request_id>/repro?<format>")]
+pub async fn reproduce(
+    id: &str,
+    request_id: &str,
+    format: Option<&str>,
+    mut auth: AuthService<Subscriber, true>,
+    mut db: Connection<AuthDb>,
+) -> Result<String, Status> {
+    auth.check(id).await?;
+    let format = format
+        .unwrap_or("curl")
+        .parse::<Format>()
+        .map_err(|_| Status::UnprocessableEntity)?;
+
+    let request = load(&mut db, id, request_id).await?;
+    Ok(repro::render(&request, format))
+}
+
+/// Every stored request of an endpoint after `seq` as sequence number and
+/// JSON, oldest first.
+pub async fn since(
+    db: &mut SqliteConnection,
+    id: &str,
+    seq: i64,
+) -> sqlx::Result<Vec<(i64, String)>> {
+    sqlx::query_as("SELECT seq, data FROM history WHERE endpoint = ? AND seq > ? ORDER BY seq;")
+        .bind(id)
+        .bind(seq)
+        .fetch_all(db)
+        .await
+}
+
+/// Sequence number of the newest stored request of an endpoint, 0 if none.
+pub async fn latest(db: &mut SqliteConnection, id: &str) -> sqlx::Result<i64> {
+    sqlx::query_scalar("SELECT COALESCE(MAX(seq), 0) FROM history WHERE endpoint = ?;")
+        .bind(id)
+        .fetch_one(db)
+        .await
+}
+
+/// Loads a captured request of an endpoint with its spooled body included.
+pub async fn load(
+    db: &mut SqliteConnection,
+    id: &str,
+    request_id: &str,
+) -> Result<CapturedRequest, Status> {
+    let mut request = find(db, id, request_id)
+        .await?
+        .and_then(captured)
+        .ok_or(Status::NotFound)?;
+    spool::inline(&mut request).await;
+    Ok(request)
+}
+
+/// Looks up a captured request of an endpoint by its id.
+async fn find(
+    db: &mut SqliteConnection,
+    id: &str,
+    request_id: &str,
+) -> Result<Option<HistoryEntry>, Status> {
+    sqlx::query_as::<_, HistoryEntry>(
+        "SELECT seq, data FROM history WHERE endpoint = ? AND request_id = ?;",
+    )
+    .bind(id)
+    .bind(request_id)
+    .fetch_optional(db)
+    .await
+    .map_err(|e| {
+        eprintln!("Could not read history: {e}");
+        Status::InternalServerError
+    })
+}
@@ -0,0 +1,48 @@
+use rocket::http::ContentType;
+use serde::Serialize;
+use serde_json::Value;
+
+/// A parsed JSON body. `value` and `pretty` keep the key order of the body.
+#[derive(Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct JsonBody {
+    value: Value,
+    pretty: String,
+    /// Compact, with object keys sorted, for comparing bodies.
+    canonical: String,
+}
+
+/// `application/json`, `text/json` and `+json` types like `application/ld+json`.
+pub fn is_json(content_type: &ContentType) -> bool {
+    let sub = content_type.sub().as_str();
+    sub.eq_ignore_ascii_case("json")
+        || sub
+            .rsplit_once('+')
+            .is_some_and(|(_, suffix)| suffix.eq_ignore_ascii_case("json"))
+}
+
+pub fn parse(bytes: &[u8]) -> serde_json::Result<JsonBody> {
+    let value: Value = serde_json::from_slice(bytes)?;
+    Ok(JsonBody {
+        pretty: serde_json::to_string_pretty(&value)?,
+        canonical: serde_json::to_string(&sorted(&value))?,
+        value,
+    })
+}
+
+fn sorted(value: &Value) -> Value {
+    match value {
+        Value::Object(map) => {
+            let mut entries: Vec<_> = map.iter().collect();
+            entries.sort_by_key(|(key, _)| *key);
+            Value::Object(
+                entries
+                    .into_iter()
+                    .map(|(key, value)| (key.clone(), sorted(value)))
+                    .collect(),
+            )
+        }
+        Value::Array(items) => Value::Array(items.iter().map(sorted).collect()),
+        _ => value.clone(),
+    }
+}
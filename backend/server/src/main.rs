@@ -3,31 +3,53 @@ extern crate rocket;
 
 use chrono::NaiveDateTime;
 use dashmap::DashMap;
-use futures_channel::mpsc::{channel, Sender};
+use futures_channel::mpsc::{channel, Receiver, Sender};
 use futures_concurrency::prelude::*;
 use rocket::fairing::AdHoc;
 use rocket::fs::{FileServer, NamedFile};
 use rocket::futures::{SinkExt, StreamExt};
-use rocket::http::Status;
+use rocket::http::{Method, Status};
+use rocket::outcome::Outcome;
+use rocket::route::{self, Handler, Route};
 use rocket::serde::json::Json;
 use std::path::PathBuf;
-use std::sync::OnceLock;
-use ws::frame::{CloseCode, CloseFrame};
+use std::sync::{Arc, OnceLock};
 
 use lazy_static::lazy_static;
 use rocket::request::FromParam;
-use rocket::{Request, State};
+use rocket::{Data, Request, State};
+use rocket_db_pools::Connection;
 use shared::{read_config, Config};
 use uuid::{Error, Uuid};
 use ws::Message;
 
 mod auth;
-use auth::{Auth, AuthService, NewAuthService};
+use auth::{Auth, AuthDb, AuthService, NewAuthService, Subscriber};
 mod request_data;
 use request_data::RequestData;
+mod buffer;
+use buffer::Buffer;
 mod cleanup;
-
-static AUTH_HEADER: &'static str = "X-Auth";
+mod encoding;
+mod endpoints;
+mod events;
+mod form;
+mod history;
+mod json;
+mod metrics;
+mod migrations;
+mod poll;
+mod protocol;
+use protocol::Protocol;
+mod replay;
+mod response;
+use response::Reply;
+mod signature;
+mod spool;
+mod tunnel;
+use tunnel::Tunnels;
+
+static AUTH_HEADER: &str = "X-Auth";
 
 static CONFIG_PATH: OnceLock<String> = OnceLock::new();
 // static CONFIG: OnceLock<Config> = OnceLock::new();
@@ -40,6 +62,7 @@ lazy_static! {
     static ref CLEANUP_TOKEN: String = nanoid::nanoid!(64);
 }
 
+#[allow(dead_code)]
 #[derive(Clone, Copy)]
 pub struct ID(Uuid);
 
@@ -59,56 +82,102 @@ pub enum WsMessage {
     Shutdown,
     ServerShutdown,
     TokenExpired,
-    Request(RequestData),
+    /// The endpoint was deleted or its token rotated.
+    TokenRevoked,
+    Request(Box<RequestData>),
 }
 
 unsafe impl Send for WsMessage {}
 unsafe impl Sync for WsMessage {}
 
-type ThingMap = DashMap<String, Vec<Sender<WsMessage>>>;
+type ThingMap = Arc<DashMap<String, Vec<Sender<WsMessage>>>>;
 
 #[catch(default)]
 fn default_catcher(_: Status, _: &Request) {}
 
-#[get("/send/<id>", data = "<input>")]
-async fn get(id: &str, auth: AuthService, map: &State<ThingMap>, input: RequestData) -> Status {
-    handle(id, auth, map, input).await
-}
-
-#[put("/send/<id>", data = "<input>")]
-async fn put(id: &str, auth: AuthService, map: &State<ThingMap>, input: RequestData) -> Status {
-    handle(id, auth, map, input).await
-}
-
-#[post("/send/<id>", data = "<input>")]
-async fn post(id: &str, auth: AuthService, map: &State<ThingMap>, input: RequestData) -> Status {
-    handle(id, auth, map, input).await
-}
-
-#[delete("/send/<id>", data = "<input>")]
-async fn delete(id: &str, auth: AuthService, map: &State<ThingMap>, input: RequestData) -> Status {
-    handle(id, auth, map, input).await
-}
-
-#[head("/send/<id>", data = "<input>")]
-async fn head(id: &str, auth: AuthService, map: &State<ThingMap>, input: RequestData) -> Status {
-    handle(id, auth, map, input).await
+// Rocket rejects methods it cannot parse (extension methods) before routing,
+// which surfaces here as a 400 for the salvaged request without a route. URIs
+// it cannot parse are replaced by `/` and never get here.
+#[catch(400)]
+fn bad_send_request(req: &Request) -> &'static str {
+    if req.route().is_some() {
+        return "";
+    }
+    eprintln!("Rejected request with unsupported method to {}", req.uri());
+    "Bad Request: unsupported method. Supported methods are GET, PUT, POST, DELETE, HEAD, \
+    OPTIONS, PATCH, TRACE and CONNECT; extension methods are not supported.\n"
 }
 
-#[options("/send/<id>", data = "<input>")]
-async fn options(id: &str, auth: AuthService, map: &State<ThingMap>, input: RequestData) -> Status {
-    handle(id, auth, map, input).await
+/// Mounts the capture endpoint for every method Rocket can parse. The route
+/// attributes only accept a subset of them, so [`Capture`] resolves its guards
+/// by hand.
+fn send_routes() -> Vec<Route> {
+    [
+        Method::Get,
+        Method::Put,
+        Method::Post,
+        Method::Delete,
+        Method::Options,
+        Method::Head,
+        Method::Trace,
+        Method::Connect,
+        Method::Patch,
+    ]
+    .into_iter()
+    .map(|method| Route::new(method, "/send/<id>/<_..>", Capture))
+    .collect()
 }
 
-#[patch("/send/<id>", data = "<input>")]
-async fn patch(id: &str, auth: AuthService, map: &State<ThingMap>, input: RequestData) -> Status {
-    handle(id, auth, map, input).await
+#[derive(Clone)]
+struct Capture;
+
+#[rocket::async_trait]
+impl Handler for Capture {
+    async fn handle<'r>(&self, req: &'r Request<'_>, data: Data<'r>) -> route::Outcome<'r> {
+        let id = match req.param::<&str>(1) {
+            Some(Ok(id)) => id,
+            _ => return route::Outcome::forward(data, Status::NotFound),
+        };
+        let mut auth = match req.guard::<AuthService>().await {
+            Outcome::Success(auth) => auth,
+            Outcome::Error((s, _)) | Outcome::Forward(s) => {
+                return route::Outcome::forward(data, s)
+            }
+        };
+        // Checked before reading the body, so unauthorized bodies are never spooled.
+        if let Err(s) = auth.check(id).await {
+            return route::Outcome::from(req, Reply::from(s));
+        }
+        let mut db = match req.guard::<Connection<AuthDb>>().await {
+            Outcome::Success(db) => db,
+            Outcome::Error((s, _)) | Outcome::Forward(s) => {
+                return route::Outcome::forward(data, s)
+            }
+        };
+        let (map, buffer, tunnels) = match (
+            req.rocket().state::<ThingMap>(),
+            req.rocket().state::<Buffer>(),
+            req.rocket().state::<Tunnels>(),
+        ) {
+            (Some(map), Some(buffer), Some(tunnels)) => (map, buffer, tunnels),
+            _ => return route::Outcome::error(Status::InternalServerError),
+        };
+        let settings = match request_data::settings(&mut db, id).await {
+            Ok(settings) => settings,
+            Err(s) => return route::Outcome::from(req, Reply::from(s)),
+        };
+        let input = RequestData::read(req, data, settings).await;
+        route::Outcome::from(req, handle(id, auth, db, map, buffer, tunnels, input).await)
+    }
 }
 
 #[post("/register/random")]
 async fn register_random(auth_service: NewAuthService) -> Result<(Status, Json<Auth>), Status> {
     match auth_service.save_random().await {
-        Ok(auth) => Ok((Status::Ok, Json(auth))),
+        Ok(auth) => {
+            metrics::REGISTRATIONS.inc();
+            Ok((Status::Ok, Json(auth)))
+        }
         Err(s) => Err(s),
     }
 }
@@ -119,7 +188,10 @@ async fn register(
     auth_service: NewAuthService,
 ) -> Result<(Status, Json<Auth>), Status> {
     match auth_service.save(auth.0).await {
-        Ok(auth) => Ok((Status::Ok, Json(auth))),
+        Ok(auth) => {
+            metrics::REGISTRATIONS.inc();
+            Ok((Status::Ok, Json(auth)))
+        }
         Err(s) => Err(s),
     }
 }
@@ -127,41 +199,97 @@ async fn register(
 // TODO clear out sockets and auths after some time
 
 #[head("/validate/<id>")]
-async fn validate(id: &str, mut auth: AuthService) -> Result<(), Status> {
+async fn validate(id: &str, mut auth: AuthService<Subscriber>) -> Result<(), Status> {
     auth.check(id).await
 }
 
 async fn handle(
     id: &str,
     mut auth: AuthService,
-    map: &State<ThingMap>,
-    input: RequestData,
-) -> Status {
-    if let Err(s) = auth.check(id).await {
-        return s;
+    mut db: Connection<AuthDb>,
+    map: &ThingMap,
+    buffer: &Buffer,
+    tunnels: &Tunnels,
+    mut input: RequestData,
+) -> Reply {
+    auth.touch(id).await;
+    metrics::CAPTURED
+        .with_label_values(&[input.method().as_str()])
+        .inc();
+    if input.truncated() {
+        metrics::TRUNCATED.inc();
+    }
+    if input.rejected() {
+        println!("Rejecting request without valid signature");
+        spool::remove(&[input.id().to_string()]).await;
+        return Status::Unauthorized.into();
     }
+    let rules = response::load(&mut db, id).await;
+    match history::store(&mut db, id, &input).await {
+        Ok(seq) => input.set_seq(seq),
+        Err(e) => eprintln!("Could not store request in history: {e}"),
+    }
+    let pending = tunnels.expect(id, input.id().to_string());
     let mut has_sent = false;
+    let mut answering = false;
     if let Some(mut senders) = map.get_mut(id) {
         println!("Found senders");
+        let mut sent = input.clone();
+        sent.set_awaits_response(pending.is_some());
         for sender in senders.value() {
             println!("Trying send");
             if !sender.is_closed() {
                 println!("Sending");
-                has_sent = true;
-                if let Err(e) = sender.clone().send(WsMessage::Request(input.clone())).await {
+                let timer = metrics::SEND_WAIT.start_timer();
+                let result = sender
+                    .clone()
+                    .send(WsMessage::Request(Box::new(sent.clone())))
+                    .await;
+                timer.observe_duration();
+                if let Err(e) = result {
                     eprintln!("Send Error (closing channel): {}", e);
+                    metrics::DELIVERIES.with_label_values(&["failed"]).inc();
                     sender.clone().close_channel();
-                };
+                } else {
+                    metrics::DELIVERIES.with_label_values(&["succeeded"]).inc();
+                    has_sent = true;
+                    answering |= tunnels.responds(id, sender);
+                }
             }
         }
 
         senders.value_mut().retain(|sender| !sender.is_closed());
     }
     if !has_sent {
-        println!("Removing, as nothing has been sent");
+        println!("Removing and buffering, as nothing has been sent");
+        metrics::DELIVERIES.with_label_values(&["buffered"]).inc();
         map.remove(id);
+        let reply = response::reply(rules, id, &input).await;
+        buffer.push(id, input);
+        return reply;
     }
-    Status::Accepted
+    // Only a responding subscriber that got the request can answer it.
+    match pending.filter(|_| answering) {
+        Some(pending) => {
+            // Other captures need connections while the subscriber answers.
+            drop(auth);
+            drop(db);
+            pending.reply().await
+        }
+        None => response::reply(rules, id, &input).await,
+    }
+}
+
+/// Registers a new subscriber of the endpoint.
+fn subscribe(map: &ThingMap, id: &str) -> Receiver<WsMessage> {
+    subscribe_sender(map, id).1
+}
+
+/// Like [`subscribe`], also returning the sender captures are delivered with.
+fn subscribe_sender(map: &ThingMap, id: &str) -> (Sender<WsMessage>, Receiver<WsMessage>) {
+    let (sender, receiver) = channel(8);
+    map.entry(id.to_owned()).or_default().push(sender.clone());
+    (sender, receiver)
 }
 
 enum MyMessage {
@@ -169,24 +297,34 @@ enum MyMessage {
     Out(WsMessage),
 }
 
-#[get("/connect/<id>")]
+#[get("/connect/<id>?<v>&<respond>")]
+#[allow(clippy::too_many_arguments)]
 fn websocket<'r>(
     id: &'r str,
-    mut auth: AuthService<true>,
+    v: Option<u32>,
+    respond: Option<bool>,
+    mut auth: AuthService<Subscriber, true>,
     ws: ws::WebSocket,
     map: &'r State<ThingMap>,
+    buffer: &'r State<Buffer>,
+    tunnels: &'r State<Tunnels>,
 ) -> ws::Stream!['r] {
-    let (sender, receiver) = channel(8);
-
+    let protocol = Protocol::negotiate(v, respond.unwrap_or(false));
     ws::Stream! { ws =>
-        if auth.check_bool(id).await {
-            if !map.contains_key(id) {
-                map.insert(id.to_owned(), vec![]);
+        // Answering requests speaks for the endpoint, like sending to it.
+        let permitted = auth.check_bool(id).await
+            && (!protocol.responds() || auth.check_as::<auth::Sender>(id).await.is_ok());
+        if permitted {
+            auth.touch(id).await;
+            let (sender, receiver) = subscribe_sender(map, id);
+            let responding = protocol.responds().then(|| tunnels.attach(id, sender));
+
+            if let Some(hello) = protocol.hello(id) {
+                yield hello;
+            }
+            for req in buffer.drain(id) {
+                yield protocol.request(&req);
             }
-            map.alter(id, |_, mut v| {
-                v.push(sender.clone());
-                v
-            });
 
             let w = ws.map(MyMessage::In);
             let re = receiver.map(MyMessage::Out);
@@ -200,6 +338,10 @@ fn websocket<'r>(
                                         println!("Websocket closed");
                                         break;
                                     },
+                                    Message::Text(text) => match protocol.receive(&text, responding.as_ref()) {
+                                        Some(reply) => yield reply,
+                                        None => println!("Websocket Message: {text}"),
+                                    },
                                     _ => println!("Websocket Message: {:?}", msg)
                                 }
                             },
@@ -210,24 +352,11 @@ fn websocket<'r>(
                         }
                     },
                     MyMessage::Out(rd) => {
-                        match rd {
-                            WsMessage::Shutdown => {
-                                yield Message::Close(None);
-                                break;
-                            },
-                            WsMessage::ServerShutdown => {
-                                yield Message::Close(Some(CloseFrame {
-                                    code: CloseCode::Away,
-                                    reason: std::borrow::Cow::Borrowed("SERVER")
-                                }))
-                            },
-                            WsMessage::TokenExpired => {
-                                yield Message::Close(Some(CloseFrame {
-                                    code: CloseCode::Library(4001),
-                                    reason: std::borrow::Cow::Borrowed("SERVER")
-                                }))
-                            },
-                            WsMessage::Request(req) => yield serde_json::to_string(&req).unwrap_or("ERROR".to_string()).into()
+                        for message in protocol.messages(&rd) {
+                            yield message;
+                        }
+                        if let WsMessage::Shutdown = rd {
+                            break;
                         }
                     }
                 }
@@ -253,25 +382,42 @@ async fn ui() -> NamedFile {
 #[launch]
 fn rocket() -> _ {
     let r = rocket::build()
-        .manage(ThingMap::new())
+        .manage(ThingMap::default())
+        .manage(Buffer::default())
+        .manage(Tunnels::default())
+        .manage(replay::client())
         .mount(
             "/",
             routes![
-                get,
-                put,
-                post,
-                delete,
-                head,
-                options,
-                patch,
                 websocket,
+                events::events,
+                poll::poll,
                 register,
                 validate,
-                register_random
+                register_random,
+                history::history,
+                history::export_har,
+                history::export_request_har,
+                history::reproduce,
+                replay::replay,
+                replay::replays,
+                response::get_response,
+                response::set_response,
+                response::delete_response,
+                endpoints::rotate,
+                endpoints::delete,
+                endpoints::renew,
+                metrics::metrics,
+                signature::get_signature,
+                signature::set_signature,
+                signature::delete_signature,
+                spool::download
             ],
         )
+        .mount("/", send_routes())
         .mount("/admin", routes![cleanup::cleanup_tokens])
         .register("/", catchers![default_catcher])
+        .register("/send", catchers![bad_send_request])
         .mount("/ui", routes![ui]);
 
     let f = r.figment();
@@ -286,24 +432,29 @@ fn rocket() -> _ {
     let r = r.mount("/ui", FileServer::from(CONFIG.ui_path()).rank(-5));
 
     cleanup::init();
-
-    auth::attach_db(r).attach(AdHoc::on_shutdown("Close Websockets", |r| {
-        Box::pin(async move {
-            if let Some(thingies) = r.state::<ThingMap>() {
-                for mut i in thingies.iter_mut() {
-                    let key = i.key().clone();
-                    for s in i.value_mut() {
-                        if !s.is_closed() {
-                            println!("Shutting down {key}");
-                            if let Err(e) = s.send(WsMessage::ServerShutdown).await {
-                                eprintln!("Could not shut down {key}: {e}")
-                            } else {
-                                println!("Shutdown for {key} successful")
+    spool::init();
+    metrics::init();
+
+    let r = auth::attach_db(r);
+
+    r.attach(cleanup::scheduler())
+        .attach(AdHoc::on_shutdown("Close Websockets", |r| {
+            Box::pin(async move {
+                if let Some(thingies) = r.state::<ThingMap>() {
+                    for mut i in thingies.iter_mut() {
+                        let key = i.key().clone();
+                        for s in i.value_mut() {
+                            if !s.is_closed() {
+                                println!("Shutting down {key}");
+                                if let Err(e) = s.send(WsMessage::ServerShutdown).await {
+                                    eprintln!("Could not shut down {key}: {e}")
+                                } else {
+                                    println!("Shutdown for {key} successful")
+                                }
                             }
                         }
                     }
                 }
-            }
-        })
-    }))
+            })
+        }))
 }
@@ -0,0 +1,86 @@
+use lazy_static::lazy_static;
+use prometheus::{
+    Encoder, Histogram, HistogramOpts, IntCounter, IntCounterVec, IntGauge, Opts, Registry,
+    TextEncoder,
+};
+use rocket::{http::ContentType, State};
+
+use crate::{cleanup::ConfigurationAuth, ThingMap};
+
+lazy_static! {
+    static ref REGISTRY: Registry = Registry::new_custom(Some("req".to_owned()), None)
+        .expect("Could not create metrics registry");
+    pub static ref CAPTURED: IntCounterVec = register(IntCounterVec::new(
+        Opts::new("captured_requests_total", "Requests captured per method"),
+        &["method"]
+    ));
+    pub static ref TRUNCATED: IntCounter = register(IntCounter::new(
+        "truncated_bodies_total",
+        "Captured bodies cut off at the body limit"
+    ));
+    /// `succeeded` and `failed` count sends to subscribers, `buffered` requests
+    /// without one.
+    pub static ref DELIVERIES: IntCounterVec = register(IntCounterVec::new(
+        Opts::new("deliveries_total", "Deliveries of captured requests by result"),
+        &["result"]
+    ));
+    /// How long sends wait for room in the channel of a subscriber.
+    pub static ref SEND_WAIT: Histogram = register(Histogram::with_opts(
+        HistogramOpts::new(
+            "send_wait_seconds",
+            "Time spent waiting to hand a request to a subscriber"
+        )
+        .buckets(vec![0.0001, 0.001, 0.01, 0.1, 1.0, 10.0])
+    ));
+    static ref SUBSCRIBERS: IntGauge = register(IntGauge::new(
+        "open_subscribers",
+        "Open websocket, event stream and poll subscriptions"
+    ));
+    pub static ref REGISTRATIONS: IntCounter = register(IntCounter::new(
+        "registrations_total",
+        "Registered endpoints"
+    ));
+    pub static ref EXPIRED: IntCounter = register(IntCounter::new(
+        "expired_tokens_total",
+        "Endpoints removed by the token cleanup"
+    ));
+}
+
+/// Registers every metric, so they are reported before their first change.
+pub fn init() {
+    lazy_static::initialize(&CAPTURED);
+    lazy_static::initialize(&TRUNCATED);
+    lazy_static::initialize(&DELIVERIES);
+    lazy_static::initialize(&SEND_WAIT);
+    lazy_static::initialize(&SUBSCRIBERS);
+    lazy_static::initialize(&REGISTRATIONS);
+    lazy_static::initialize(&EXPIRED);
+}
+
+fn register<M: prometheus::core::Collector + Clone + 'static>(metric: prometheus::Result<M>) -> M {
+    let metric = metric.expect("Invalid metric");
+    REGISTRY
+        .register(Box::new(metric.clone()))
+        .expect("Could not register metric");
+    metric
+}
+
+/// Metrics in the Prometheus text format. Scrapers authenticate with the admin
+/// token from `secret_path`, in `X-Auth` or as bearer token, so Prometheus can
+/// read it with `authorization: { credentials_file: <secret_path> }`.
+#[get("/metrics")]
+pub fn metrics(_ca: ConfigurationAuth, map: &State<ThingMap>) -> (ContentType, String) {
+    SUBSCRIBERS.set(
+        map.iter()
+            .map(|entry| entry.value().iter().filter(|s| !s.is_closed()).count() as i64)
+            .sum(),
+    );
+    let mut buffer = vec![];
+    if let Err(e) = TextEncoder::new().encode(&REGISTRY.gather(), &mut buffer) {
+        eprintln!("Could not encode metrics: {e}");
+    }
+    (
+        ContentType::new("text", "plain").with_params(("version", "0.0.4")),
+        String::from_utf8_lossy(&buffer).into_owned(),
+    )
+}
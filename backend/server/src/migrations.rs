@@ -0,0 +1,251 @@
+use std::fmt;
+
+use futures::future::BoxFuture;
+use rocket::{Build, Rocket};
+use rocket_db_pools::{
+    sqlx::{self, SqliteConnection, SqlitePool},
+    Database,
+};
+use shared::custom_timestamp;
+
+use crate::{
+    auth::{self, AuthDb},
+    CONFIG, MY_EPOCH,
+};
+
+struct Migration {
+    version: i64,
+    description: &'static str,
+    steps: &'static [Step],
+}
+
+enum Step {
+    Sql(&'static str),
+    /// Data migrations that cannot be expressed in SQL.
+    Rust(for<'c> fn(&'c mut SqliteConnection) -> BoxFuture<'c, sqlx::Result<()>>),
+}
+
+/// Forward migrations of the auth database, applied in order inside one
+/// transaction each. Released migrations must not be changed, append a new
+/// one instead.
+const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        description: "Initial schema",
+        // Before migrations existed the tables were dropped on every start, so
+        // whatever is left of them carries nothing worth keeping.
+        steps: &[
+            Step::Sql("DROP TABLE IF EXISTS auth;"),
+            Step::Sql("DROP TABLE IF EXISTS history;"),
+            Step::Sql(
+                "CREATE TABLE auth (
+                id TEXT PRIMARY KEY,
+                token TEXT,
+                ts INTEGER,
+                response TEXT,
+                body_limit INTEGER);",
+            ),
+            Step::Sql(
+                "CREATE TABLE history (
+                seq INTEGER PRIMARY KEY AUTOINCREMENT,
+                endpoint TEXT NOT NULL,
+                request_id TEXT NOT NULL,
+                method TEXT NOT NULL,
+                path TEXT NOT NULL,
+                data TEXT NOT NULL,
+                ts INTEGER);",
+            ),
+            Step::Sql("CREATE INDEX history_endpoint ON history (endpoint, seq);"),
+        ],
+    },
+    Migration {
+        version: 2,
+        description: "Hash stored tokens",
+        steps: &[
+            Step::Sql("ALTER TABLE auth ADD COLUMN token_hash TEXT NOT NULL DEFAULT '';"),
+            Step::Rust(hash_tokens),
+            Step::Sql("ALTER TABLE auth DROP COLUMN token;"),
+        ],
+    },
+    Migration {
+        version: 3,
+        description: "Separate send and read tokens",
+        steps: &[
+            Step::Sql("ALTER TABLE auth ADD COLUMN send_hash TEXT;"),
+            Step::Sql("ALTER TABLE auth ADD COLUMN read_hash TEXT;"),
+        ],
+    },
+    Migration {
+        version: 4,
+        description: "Per-endpoint time-to-live",
+        steps: &[
+            Step::Sql("ALTER TABLE auth ADD COLUMN ttl INTEGER;"),
+            Step::Sql("ALTER TABLE auth ADD COLUMN expires INTEGER;"),
+            Step::Rust(default_expiry),
+        ],
+    },
+    Migration {
+        version: 5,
+        description: "Signature verification",
+        steps: &[Step::Sql("ALTER TABLE auth ADD COLUMN signature TEXT;")],
+    },
+    Migration {
+        version: 6,
+        description: "Replays",
+        steps: &[
+            Step::Sql(
+                "CREATE TABLE replays (
+                seq INTEGER PRIMARY KEY AUTOINCREMENT,
+                endpoint TEXT NOT NULL,
+                request_id TEXT NOT NULL,
+                data TEXT NOT NULL,
+                ts INTEGER);",
+            ),
+            Step::Sql("CREATE INDEX replays_request ON replays (endpoint, request_id, seq);"),
+        ],
+    },
+    Migration {
+        version: 7,
+        description: "Drop captured credentials",
+        steps: &[Step::Sql(
+            "UPDATE history SET data = json_remove(data,
+                '$.headers.\"x-auth\"', '$.headers.\"proxy-authorization\"');",
+        )],
+    },
+    Migration {
+        version: 8,
+        description: "Key for generated tokens",
+        steps: &[
+            Step::Sql("CREATE TABLE secrets (name TEXT PRIMARY KEY, value BLOB NOT NULL);"),
+            Step::Rust(token_key),
+        ],
+    },
+];
+
+fn hash_tokens(db: &mut SqliteConnection) -> BoxFuture<'_, sqlx::Result<()>> {
+    Box::pin(async move {
+        let tokens = sqlx::query_as::<_, (String, String)>(
+            "SELECT id, token FROM auth WHERE token IS NOT NULL AND token != '';",
+        )
+        .fetch_all(&mut *db)
+        .await?;
+        for (id, token) in tokens {
+            let hash =
+                auth::hash_token(&token).map_err(|e| sqlx::Error::Protocol(e.to_string()))?;
+            sqlx::query("UPDATE auth SET token_hash = ? WHERE id = ?;")
+                .bind(hash)
+                .bind(id)
+                .execute(&mut *db)
+                .await?;
+        }
+        Ok(())
+    })
+}
+
+/// Endpoints registered before the time-to-live existed expire `max_age`
+/// after their creation, as they did before.
+fn default_expiry(db: &mut SqliteConnection) -> BoxFuture<'_, sqlx::Result<()>> {
+    Box::pin(async move {
+        sqlx::query("UPDATE auth SET ttl = ?, expires = ts + ?;")
+            .bind(CONFIG.max_age())
+            .bind(CONFIG.max_age())
+            .execute(db)
+            .await?;
+        Ok(())
+    })
+}
+
+fn token_key(db: &mut SqliteConnection) -> BoxFuture<'_, sqlx::Result<()>> {
+    Box::pin(async move {
+        sqlx::query("INSERT INTO secrets (name, value) VALUES (?, ?);")
+            .bind(auth::TOKEN_KEY)
+            .bind(auth::generate_key().to_vec())
+            .execute(db)
+            .await?;
+        Ok(())
+    })
+}
+
+#[derive(Debug)]
+pub enum MigrationError {
+    Sql(sqlx::Error),
+    /// The database was migrated by a newer version of the server.
+    UnknownVersion {
+        found: i64,
+        latest: i64,
+    },
+}
+
+impl fmt::Display for MigrationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MigrationError::Sql(e) => write!(f, "{e}"),
+            MigrationError::UnknownVersion { found, latest } => write!(
+                f,
+                "schema version {found} is newer than the latest known version {latest}"
+            ),
+        }
+    }
+}
+
+impl From<sqlx::Error> for MigrationError {
+    fn from(e: sqlx::Error) -> Self {
+        MigrationError::Sql(e)
+    }
+}
+
+pub async fn run(rocket: Rocket<Build>) -> Result<Rocket<Build>, Rocket<Build>> {
+    let db = AuthDb::fetch(&rocket).unwrap();
+    if let Err(e) = migrate(db).await {
+        eprintln!("Could not migrate AuthDB: {e}");
+        Err(rocket)
+    } else {
+        Ok(rocket)
+    }
+}
+
+async fn migrate(pool: &SqlitePool) -> Result<(), MigrationError> {
+    sqlx::query(
+        "CREATE TABLE IF NOT EXISTS schema_version (
+            version INTEGER PRIMARY KEY,
+            description TEXT NOT NULL,
+            ts INTEGER)",
+    )
+    .execute(pool)
+    .await?;
+
+    let current: i64 = sqlx::query_scalar("SELECT COALESCE(MAX(version), 0) FROM schema_version;")
+        .fetch_one(pool)
+        .await?;
+    let latest = MIGRATIONS.last().map_or(0, |m| m.version);
+    if current > latest {
+        return Err(MigrationError::UnknownVersion {
+            found: current,
+            latest,
+        });
+    }
+
+    for migration in MIGRATIONS.iter().filter(|m| m.version > current) {
+        println!(
+            "Migrating AuthDB to version {}: {}",
+            migration.version, migration.description
+        );
+        let mut tx = pool.begin().await?;
+        for step in migration.steps {
+            match step {
+                Step::Sql(statement) => {
+                    sqlx::query(statement).execute(&mut *tx).await?;
+                }
+                Step::Rust(f) => f(&mut tx).await?,
+            }
+        }
+        sqlx::query("INSERT INTO schema_version (version, description, ts) VALUES (?, ?, ?);")
+            .bind(migration.version)
+            .bind(migration.description)
+            .bind(custom_timestamp(*MY_EPOCH))
+            .execute(&mut *tx)
+            .await?;
+        tx.commit().await?;
+    }
+    Ok(())
+}
@@ -0,0 +1,199 @@
+use std::time::Duration;
+
+use rocket::{
+    futures::StreamExt,
+    http::Status,
+    serde::json::Json,
+    tokio::time::{timeout_at, Instant},
+    State,
+};
+use rocket_db_pools::{
+    sqlx::{self, SqliteConnection},
+    Connection,
+};
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::{
+    auth::{AuthDb, AuthService, Subscriber},
+    history::{self, HistoryEntry, MAX_PAGE_SIZE},
+    request_data::RequestData,
+    subscribe, ThingMap, WsMessage,
+};
+
+const DEFAULT_WAIT: Duration = Duration::from_secs(30);
+const MAX_WAIT: Duration = Duration::from_secs(120);
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PollBatch {
+    items: Vec<HistoryEntry>,
+    /// Pass as `after` to the next poll.
+    cursor: i64,
+}
+
+/// The time to wait for requests, [`DEFAULT_WAIT`] if not given and at most
+/// [`MAX_WAIT`]. `None` if the wait cannot be parsed.
+fn parse_wait(wait: Option<&str>) -> Option<Duration> {
+    match wait {
+        Some(wait) => parse_duration(wait).map(|wait| wait.min(MAX_WAIT)),
+        None => Some(DEFAULT_WAIT),
+    }
+}
+
+/// Parses durations like `30s`, `500ms`, `2m` or plain seconds.
+fn parse_duration(wait: &str) -> Option<Duration> {
+    let wait = wait.trim();
+    let unit = wait
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(wait.len());
+    let (number, unit) = wait.split_at(unit);
+    let number: u64 = number.parse().ok()?;
+    match unit {
+        "ms" => Some(Duration::from_millis(number)),
+        "" | "s" => Some(Duration::from_secs(number)),
+        "m" => Some(Duration::from_secs(number.saturating_mul(60))),
+        _ => None,
+    }
+}
+
+/// Stored requests after the cursor, oldest first, and the cursor after them.
+async fn stored(
+    db: &mut SqliteConnection,
+    id: &str,
+    after: Option<i64>,
+) -> sqlx::Result<(Vec<HistoryEntry>, i64)> {
+    let mut cursor = match after {
+        Some(after) => after,
+        None => history::latest(db, id).await?,
+    };
+    let mut items = vec![];
+    for (seq, data) in history::since(db, id, cursor)
+        .await?
+        .into_iter()
+        .take(MAX_PAGE_SIZE as usize)
+    {
+        items.push(HistoryEntry::new(
+            seq,
+            serde_json::from_str(&data).unwrap_or(Value::Null),
+        ));
+        cursor = seq;
+    }
+    Ok((items, cursor))
+}
+
+/// Adds a live request unless the cursor already covers it.
+fn push(items: &mut Vec<HistoryEntry>, cursor: &mut i64, req: &RequestData) {
+    if req.seq().is_some_and(|seq| seq <= *cursor) {
+        return;
+    }
+    let seq = req.seq().unwrap_or(*cursor);
+    items.push(HistoryEntry::new(
+        seq,
+        serde_json::to_value(req).unwrap_or(Value::Null),
+    ));
+    *cursor = seq;
+}
+
+/// Returns the requests stored after the `after` cursor, or waits up to
+/// `wait` for the next ones. Without a cursor only requests arriving from now
+/// on are returned. An empty batch means the wait elapsed.
+#[get("/poll/<id>?<wait>&<after>")]
+pub async fn poll(
+    id: &str,
+    mut auth: AuthService<Subscriber, true>,
+    mut db: Connection<AuthDb>,
+    wait: Option<&str>,
+    after: Option<i64>,
+    map: &State<ThingMap>,
+) -> Result<Json<PollBatch>, Status> {
+    auth.check(id).await?;
+    auth.touch(id).await;
+    let wait = parse_wait(wait).ok_or(Status::UnprocessableEntity)?;
+    let deadline = Instant::now() + wait;
+
+    // Subscribe before reading the history, so nothing slips in between.
+    let mut receiver = subscribe(map, id);
+
+    let (mut items, mut cursor) = stored(&mut db, id, after).await.map_err(|e| {
+        eprintln!("Could not read history: {e}");
+        Status::InternalServerError
+    })?;
+
+    // Holding connections for the whole wait would starve the pool.
+    drop(db);
+    drop(auth);
+
+    let mut gone = false;
+    while items.is_empty() {
+        let Ok(Some(message)) = timeout_at(deadline, receiver.next()).await else {
+            break;
+        };
+        match message {
+            WsMessage::Request(req) => {
+                push(&mut items, &mut cursor, &req);
+                // Include whatever arrived together with it.
+                while let Ok(Some(WsMessage::Request(req))) = receiver.try_next() {
+                    push(&mut items, &mut cursor, &req);
+                }
+            }
+            WsMessage::TokenExpired | WsMessage::TokenRevoked => {
+                gone = true;
+                break;
+            }
+            WsMessage::ServerShutdown | WsMessage::Shutdown => break,
+        }
+    }
+
+    drop(receiver);
+    if let Some(mut senders) = map.get_mut(id) {
+        senders.retain(|sender| !sender.is_closed());
+    }
+    if gone {
+        return Err(Status::Gone);
+    }
+    Ok(Json(PollBatch { items, cursor }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn missing_wait_is_the_default() {
+        assert_eq!(parse_wait(None), Some(DEFAULT_WAIT));
+    }
+
+    #[test]
+    fn zero_wait_returns_at_once() {
+        for wait in ["0", "0s", "0ms", "0m"] {
+            assert_eq!(parse_wait(Some(wait)), Some(Duration::ZERO), "{wait}");
+        }
+    }
+
+    #[test]
+    fn wait_is_capped() {
+        assert_eq!(parse_wait(Some("500ms")), Some(Duration::from_millis(500)));
+        assert_eq!(parse_wait(Some(" 45 ")), Some(Duration::from_secs(45)));
+        assert_eq!(parse_wait(Some("2m")), Some(MAX_WAIT));
+        assert_eq!(parse_wait(Some("3m")), Some(MAX_WAIT));
+        assert_eq!(parse_wait(Some("121s")), Some(MAX_WAIT));
+        assert_eq!(parse_wait(Some("18446744073709551615m")), Some(MAX_WAIT));
+    }
+
+    #[test]
+    fn non_numeric_wait_is_rejected() {
+        for wait in [
+            "",
+            "s",
+            "abc",
+            "-1",
+            "1.5s",
+            "10h",
+            "5 s",
+            "18446744073709551616",
+        ] {
+            assert_eq!(parse_wait(Some(wait)), None, "{wait}");
+        }
+    }
+}
@@ -0,0 +1,120 @@
+use std::borrow::Cow;
+
+use shared::protocol::{ClientMessage, Envelope, ServerMessage, VERSION};
+use ws::{
+    frame::{CloseCode, CloseFrame},
+    Message,
+};
+
+use crate::{request_data::RequestData, tunnel::Responding, WsMessage};
+
+/// The protocol negotiated with a websocket client, see [`shared::protocol`].
+#[derive(Clone, Copy)]
+pub struct Protocol {
+    version: u32,
+    respond: bool,
+}
+
+impl Protocol {
+    /// Answering requests needs version 1.
+    pub fn negotiate(requested: Option<u32>, respond: bool) -> Self {
+        let version = requested.unwrap_or(0).min(VERSION);
+        Protocol {
+            version,
+            respond: respond && version > 0,
+        }
+    }
+
+    pub fn responds(&self) -> bool {
+        self.respond
+    }
+
+    fn envelope(&self, message: ServerMessage<&RequestData>) -> Message {
+        serde_json::to_string(&Envelope::new(self.version, message))
+            .unwrap_or("ERROR".to_string())
+            .into()
+    }
+
+    fn error(&self, message: String) -> Message {
+        self.envelope(ServerMessage::Error { message })
+    }
+
+    pub fn hello(&self, id: &str) -> Option<Message> {
+        (self.version > 0).then(|| {
+            self.envelope(ServerMessage::Hello {
+                endpoint: id.to_owned(),
+                respond: self.respond,
+            })
+        })
+    }
+
+    pub fn request(&self, req: &RequestData) -> Message {
+        if self.version == 0 {
+            return serde_json::to_string(req)
+                .unwrap_or("ERROR".to_string())
+                .into();
+        }
+        self.envelope(ServerMessage::Request {
+            id: req.id().to_string(),
+            seq: req.seq(),
+            respond: self.respond && req.awaits_response(),
+            request: req,
+        })
+    }
+
+    /// The messages to send for a message of the endpoint. Everything but
+    /// requests ends in a close frame.
+    pub fn messages(&self, message: &WsMessage) -> Vec<Message> {
+        let (announcement, close) = match message {
+            WsMessage::Request(req) => return vec![self.request(req)],
+            WsMessage::Shutdown => (ServerMessage::Closed, None),
+            WsMessage::ServerShutdown => (ServerMessage::Shutdown, Some(close(CloseCode::Away))),
+            WsMessage::TokenExpired => (
+                ServerMessage::Expired,
+                Some(close(CloseCode::Library(4001))),
+            ),
+            WsMessage::TokenRevoked => (
+                ServerMessage::Revoked,
+                Some(close(CloseCode::Library(4002))),
+            ),
+        };
+        let mut messages = vec![];
+        if self.version > 0 {
+            messages.push(self.envelope(announcement));
+        }
+        messages.push(Message::Close(close));
+        messages
+    }
+
+    /// Handles a text message of the client, returning the answer to it.
+    /// Version 0 clients get no answer.
+    pub fn receive(&self, text: &str, responding: Option<&Responding>) -> Option<Message> {
+        if self.version == 0 {
+            return None;
+        }
+        let message = match serde_json::from_str::<Envelope<ClientMessage>>(text) {
+            Ok(envelope) => envelope.message,
+            Err(e) => return Some(self.error(e.to_string())),
+        };
+        match message {
+            ClientMessage::Ping { data } => Some(self.envelope(ServerMessage::Pong { data })),
+            ClientMessage::Response(response) => {
+                let Some(responding) = responding else {
+                    return Some(
+                        self.error("Connect with respond=true to answer requests".to_owned()),
+                    );
+                };
+                let id = response.id.clone();
+                (!responding.answer(response))
+                    .then(|| self.error(format!("Request {id} is not waiting for a response")))
+            }
+        }
+    }
+}
+
+fn close(code: CloseCode) -> CloseFrame<'static> {
+    CloseFrame {
+        code,
+        reason: Cow::Borrowed("SERVER"),
+    }
+}
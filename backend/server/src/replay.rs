@@ -0,0 +1,380 @@
+use std::{
+    collections::BTreeMap,
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use chrono::Utc;
+use hyper::client::connect::dns::Name;
+use reqwest::{
+    dns::{Addrs, Resolve, Resolving},
+    redirect, Client, Method, Response, Url,
+};
+use rocket::{http::Status, serde::json::Json, tokio::net::lookup_host, State};
+use rocket_db_pools::{
+    sqlx::{self, SqliteConnection},
+    Connection,
+};
+use serde::{Deserialize, Serialize};
+use shared::{custom_timestamp, CapturedBody, CapturedRequest};
+
+use base64::{engine::general_purpose::STANDARD as Base64, Engine as _};
+
+use crate::{
+    auth::{AuthDb, AuthService, Owner, Subscriber},
+    history::{self, HistoryEntry},
+    CONFIG, MY_EPOCH,
+};
+
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Client for replays. Redirects are recorded rather than followed, and host
+/// names only resolve to addresses replays may reach.
+pub fn client() -> Client {
+    Client::builder()
+        .timeout(TIMEOUT)
+        .redirect(redirect::Policy::none())
+        .dns_resolver(Arc::new(ReachableResolver))
+        .build()
+        .expect("Could not create replay client")
+}
+
+/// Leaves out addresses replays may not reach when connecting, so a host name
+/// cannot be pointed at an internal address after [`check_target`].
+struct ReachableResolver;
+
+impl Resolve for ReachableResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        Box::pin(async move {
+            let host = name.as_str();
+            let addrs: Vec<SocketAddr> = lookup_host((host, 0))
+                .await?
+                .filter(|addr| reachable(host, addr.ip()))
+                .collect();
+            if addrs.is_empty() {
+                return Err(format!("{host} does not resolve to a public address").into());
+            }
+            Ok(Box::new(addrs.into_iter()) as Addrs)
+        })
+    }
+}
+
+/// Rejects targets on loopback, private, link-local and other non-public
+/// addresses, unless `replay_allow` lists them.
+async fn check_target(url: &Url) -> Result<(), Status> {
+    let host = url.host_str().ok_or(Status::UnprocessableEntity)?;
+    let literal = host.trim_start_matches('[').trim_end_matches(']');
+    let addrs: Vec<IpAddr> = match literal.parse::<IpAddr>() {
+        Ok(ip) => vec![ip],
+        // Unresolvable hosts fail when sending, which is recorded.
+        Err(_) => match lookup_host((host, 0)).await {
+            Ok(addrs) => addrs.map(|addr| addr.ip()).collect(),
+            Err(_) => return Ok(()),
+        },
+    };
+    if addrs.iter().all(|ip| reachable(host, *ip)) {
+        Ok(())
+    } else {
+        println!("Refusing to replay to {host}");
+        Err(Status::Forbidden)
+    }
+}
+
+fn reachable(host: &str, ip: IpAddr) -> bool {
+    is_public(ip) || allowed(&CONFIG.replay_allow(), host, ip)
+}
+
+fn is_public(ip: IpAddr) -> bool {
+    match ip.to_canonical() {
+        IpAddr::V4(ip) => {
+            let [a, b, ..] = ip.octets();
+            !(ip.is_private()
+                || ip.is_loopback()
+                || ip.is_link_local()
+                || ip.is_unspecified()
+                || ip.is_broadcast()
+                || ip.is_multicast()
+                || ip.is_documentation()
+                || a == 0
+                // Shared address space of carrier-grade NATs.
+                || (a == 100 && b & 0xc0 == 64))
+        }
+        IpAddr::V6(ip) => {
+            !(ip.is_loopback()
+                || ip.is_unspecified()
+                || ip.is_multicast()
+                || ip.is_unique_local()
+                || ip.is_unicast_link_local())
+        }
+    }
+}
+
+/// Whether an allowlist entry names the host, the address or a range
+/// containing it.
+fn allowed(entries: &[&str], host: &str, ip: IpAddr) -> bool {
+    let ip = ip.to_canonical();
+    entries.iter().any(|entry| match entry.split_once('/') {
+        Some((network, length)) => match (network.parse::<IpAddr>(), length.parse::<u32>()) {
+            (Ok(network), Ok(length)) => in_range(network.to_canonical(), length, ip),
+            _ => false,
+        },
+        None => match entry.parse::<IpAddr>() {
+            Ok(allowed) => allowed.to_canonical() == ip,
+            Err(_) => entry
+                .trim_end_matches('.')
+                .eq_ignore_ascii_case(host.trim_end_matches('.')),
+        },
+    })
+}
+
+fn in_range(network: IpAddr, length: u32, ip: IpAddr) -> bool {
+    let (network, ip, bits) = match (network, ip) {
+        (IpAddr::V4(network), IpAddr::V4(ip)) => {
+            (u32::from(network).into(), u32::from(ip).into(), 32)
+        }
+        (IpAddr::V6(network), IpAddr::V6(ip)) => (u128::from(network), u128::from(ip), 128),
+        _ => return false,
+    };
+    if length > bits {
+        return false;
+    }
+    let shift = bits - length;
+    network.checked_shr(shift).unwrap_or(0) == ip.checked_shr(shift).unwrap_or(0)
+}
+
+/// Where to send a captured request again, and what to change about it.
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ReplayRequest {
+    url: String,
+    #[serde(default)]
+    method: Option<String>,
+    /// Replaces headers of the captured request by name, `null` removes one.
+    #[serde(default)]
+    headers: BTreeMap<String, Option<String>>,
+    #[serde(default)]
+    body: Option<String>,
+    /// Binary replacement body, takes precedence over `body`.
+    #[serde(default)]
+    body_base64: Option<String>,
+}
+
+/// The response of the target to a replay. Without a `status` the request
+/// failed, see `error`.
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ReplayResult {
+    request_id: String,
+    method: String,
+    url: String,
+    status: Option<u16>,
+    headers: BTreeMap<String, Vec<String>>,
+    body: Option<CapturedBody>,
+    /// Whether the body was read completely, it is cut off at the inline body
+    /// limit.
+    complete: bool,
+    latency_ms: u64,
+    error: Option<String>,
+    time: String,
+}
+
+/// Sends a stored request to `url` and records the response of the target.
+#[post(
+    "/history/<id>/<request_id>/replay",
+    format = "json",
+    data = "<replay>"
+)]
+pub async fn replay(
+    id: &str,
+    request_id: &str,
+    mut auth: AuthService<Owner>,
+    mut db: Connection<AuthDb>,
+    client: &State<Client>,
+    replay: Json<ReplayRequest>,
+) -> Result<Json<ReplayResult>, Status> {
+    auth.check(id).await?;
+    let url = Url::parse(&replay.url)
+        .ok()
+        .filter(|url| matches!(url.scheme(), "http" | "https"))
+        .ok_or(Status::UnprocessableEntity)?;
+    check_target(&url).await?;
+    let request = history::load(&mut db, id, request_id).await?;
+    let method = replay.method.as_deref().unwrap_or(&request.method);
+    let method = Method::from_bytes(method.as_bytes()).map_err(|_| Status::UnprocessableEntity)?;
+    let body = match (&replay.body_base64, &replay.body) {
+        (Some(base64), _) => Base64
+            .decode(base64)
+            .map_err(|_| Status::UnprocessableEntity)?,
+        (None, Some(body)) => body.clone().into_bytes(),
+        (None, None) => request.body_bytes(),
+    };
+
+    let result = send(client, &request, &replay, method, url, body).await;
+    if let Err(e) = store(&mut db, id, &result).await {
+        eprintln!("Could not store replay of {request_id}: {e}");
+    }
+    Ok(Json(result))
+}
+
+async fn send(
+    client: &Client,
+    request: &CapturedRequest,
+    replay: &ReplayRequest,
+    method: Method,
+    url: Url,
+    body: Vec<u8>,
+) -> ReplayResult {
+    let mut builder = client.request(method.clone(), url.clone());
+    for (name, value) in request
+        .end_to_end_headers()
+        .filter(|(name, _)| !replay.headers.keys().any(|n| n.eq_ignore_ascii_case(name)))
+    {
+        builder = builder.header(name, value);
+    }
+    for (name, value) in &replay.headers {
+        if let Some(value) = value {
+            builder = builder.header(name, value);
+        }
+    }
+
+    let mut result = ReplayResult {
+        request_id: request.id.clone(),
+        method: method.to_string(),
+        url: url.to_string(),
+        status: None,
+        headers: BTreeMap::new(),
+        body: None,
+        complete: true,
+        latency_ms: 0,
+        error: None,
+        time: Utc::now().to_rfc3339(),
+    };
+    let start = Instant::now();
+    match builder.body(body).send().await {
+        Ok(res) => {
+            result.status = Some(res.status().as_u16());
+            for (name, value) in res.headers() {
+                result
+                    .headers
+                    .entry(name.to_string())
+                    .or_default()
+                    .push(String::from_utf8_lossy(value.as_bytes()).into_owned());
+            }
+            match read_body(res).await {
+                Ok((body, complete)) => {
+                    result.body = Some(body);
+                    result.complete = complete;
+                }
+                Err(e) => result.error = Some(e.to_string()),
+            }
+        }
+        Err(e) => result.error = Some(e.to_string()),
+    }
+    result.latency_ms = start.elapsed().as_millis() as u64;
+    result
+}
+
+/// Reads the response body up to the inline body limit.
+async fn read_body(mut res: Response) -> reqwest::Result<(CapturedBody, bool)> {
+    let limit = CONFIG.inline_body_limit() as usize;
+    let mut bytes = Vec::new();
+    let mut complete = true;
+    while let Some(chunk) = res.chunk().await? {
+        bytes.extend_from_slice(&chunk);
+        if bytes.len() > limit {
+            bytes.truncate(limit);
+            complete = false;
+            break;
+        }
+    }
+    Ok((CapturedBody::new(&bytes), complete))
+}
+
+async fn store(db: &mut SqliteConnection, id: &str, result: &ReplayResult) -> sqlx::Result<()> {
+    let data = serde_json::to_string(result).map_err(|e| sqlx::Error::Protocol(e.to_string()))?;
+    sqlx::query("INSERT INTO replays (endpoint, request_id, data, ts) VALUES (?, ?, ?, ?);")
+        .bind(id)
+        .bind(&result.request_id)
+        .bind(data)
+        .bind(custom_timestamp(*MY_EPOCH))
+        .execute(db)
+        .await?;
+    Ok(())
+}
+
+/// Lists the recorded replays of a request, newest first.
+#[get("/history/<id>/<request_id>/replays")]
+pub async fn replays(
+    id: &str,
+    request_id: &str,
+    mut auth: AuthService<Subscriber, true>,
+    mut db: Connection<AuthDb>,
+) -> Result<Json<Vec<HistoryEntry>>, Status> {
+    auth.check(id).await?;
+    sqlx::query_as::<_, HistoryEntry>(
+        "SELECT seq, data FROM replays WHERE endpoint = ? AND request_id = ? ORDER BY seq DESC;",
+    )
+    .bind(id)
+    .bind(request_id)
+    .fetch_all(&mut **db)
+    .await
+    .map(Json)
+    .map_err(|e| {
+        eprintln!("Could not read replays: {e}");
+        Status::InternalServerError
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(ip: &str) -> IpAddr {
+        ip.parse().unwrap()
+    }
+
+    #[test]
+    fn internal_addresses_are_not_public() {
+        for internal in [
+            "127.0.0.1",
+            "10.1.2.3",
+            "172.16.0.1",
+            "192.168.1.1",
+            "169.254.169.254",
+            "100.64.0.1",
+            "0.0.0.0",
+            "::1",
+            "::",
+            "fd00::1",
+            "fe80::1",
+            "::ffff:127.0.0.1",
+            "::ffff:169.254.169.254",
+        ] {
+            assert!(!is_public(ip(internal)), "{internal}");
+        }
+        for public in [
+            "1.1.1.1",
+            "100.128.0.1",
+            "2606:4700::1111",
+            "::ffff:8.8.8.8",
+        ] {
+            assert!(is_public(ip(public)), "{public}");
+        }
+    }
+
+    #[test]
+    fn allowlist_matches_hosts_addresses_and_ranges() {
+        let entries = ["internal.example", "127.0.0.1", "10.0.0.0/8", "fd00::/8"];
+        assert!(allowed(&entries, "Internal.Example.", ip("192.168.0.1")));
+        assert!(allowed(&entries, "localhost", ip("127.0.0.1")));
+        assert!(allowed(&entries, "localhost", ip("::ffff:127.0.0.1")));
+        assert!(allowed(&entries, "x", ip("10.255.0.1")));
+        assert!(allowed(&entries, "x", ip("fd12::1")));
+        assert!(!allowed(&entries, "x", ip("11.0.0.1")));
+        assert!(!allowed(&entries, "x", ip("127.0.0.2")));
+        assert!(!allowed(&entries, "x", ip("fe80::1")));
+        assert!(allowed(&["0.0.0.0/0"], "x", ip("192.168.0.1")));
+        assert!(!allowed(&["10.0.0.0/33", "bogus/8"], "x", ip("10.0.0.1")));
+    }
+}
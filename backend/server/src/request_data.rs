@@ -6,22 +6,38 @@ use std::{
 use chrono::{DateTime, Utc};
 use multimap::MultiMap;
 use rocket::{
-    data::Outcome,
-    data::{FromData, ToByteUnit},
+    data::ToByteUnit,
     http::{
         ext::IntoOwned,
         uri::{Host, Origin},
-        Method,
+        ContentType, HeaderMap, Method, Status,
     },
-    serde, Data, Request,
+    serde,
+    tokio::{
+        fs::File,
+        io::{self, AsyncReadExt, AsyncWriteExt},
+    },
+    Data, Request,
 };
+use rocket_db_pools::sqlx::{self, SqliteConnection};
 use serde::{Deserialize, Serialize};
+use shared::is_credential_header;
+use uuid::Uuid;
 
 use base64::{engine::general_purpose::STANDARD as Base64, Engine as _};
 
+use crate::{
+    encoding,
+    form::{self, Part},
+    json::{self, JsonBody},
+    signature::{SignatureConfig, Verdict, Verifier},
+    spool, CONFIG,
+};
+
 #[derive(Clone, Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct RequestData {
+    id: Uuid,
     method: Method,
     content_type: Option<String>,
     body: Option<Body>,
@@ -29,23 +45,237 @@ pub struct RequestData {
     headers: MultiMap<String, String>,
     cookies: MultiMap<String, String>,
     uri: Origin<'static>,
+    path: String,
     remote: RemoteInfo,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    signature: Option<Verdict>,
+    #[serde(skip)]
+    reject_unsigned: bool,
+    /// Position in the history of the endpoint, once stored.
+    #[serde(skip)]
+    seq: Option<i64>,
+    /// Whether the caller waits for a subscriber to answer.
+    #[serde(skip)]
+    awaits_response: bool,
     // accepts: Option<> // TODO
     time: String,
 }
 
+/// A captured body. Bodies above the inline limit are spooled to disk and only
+/// referenced through `download`. Inline bodies with a known `Content-Encoding`
+/// are decoded up to the body limit, the bytes as sent are kept in `original`.
+/// Decoded bodies above the inline limit are spooled decoded, other spooled
+/// bodies are stored as sent, `undecoded` tells their encoding. Inline form bodies are also parsed into `fields` or `parts`, JSON
+/// bodies into `json`.
 #[derive(Clone, Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct Body {
-    raw: String,
-    base64: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    raw: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    base64: Option<String>,
+    size: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    download: Option<String>,
+    /// The `Content-Encoding` that was decoded.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    encoding: Option<String>,
+    /// Base64 of the body before decoding.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    original: Option<String>,
+    /// The `Content-Encoding` of a body that was left encoded, because it was
+    /// spooled, cut off or could not be decoded.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    undecoded: Option<String>,
+    /// Fields of an `application/x-www-form-urlencoded` body.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    fields: Option<MultiMap<String, String>>,
+    /// Parts of a `multipart/form-data` body.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    parts: Option<Vec<Part>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    json: Option<JsonBody>,
+    /// Why a body with a JSON content type could not be parsed.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    json_error: Option<String>,
 }
 
 impl Body {
     fn from_bytes(bytes: &[u8]) -> Self {
         let raw = String::from_utf8_lossy(bytes).into_owned();
         let base64 = Base64.encode(bytes);
-        Self { raw, base64 }
+        Self {
+            raw: Some(raw),
+            base64: Some(base64),
+            size: bytes.len() as u64,
+            download: None,
+            encoding: None,
+            original: None,
+            undecoded: None,
+            fields: None,
+            parts: None,
+            json: None,
+            json_error: None,
+        }
+    }
+
+    fn decoded(encoding: String, original: &[u8], decoded: &[u8]) -> Self {
+        Self {
+            encoding: Some(encoding),
+            original: Some(Base64.encode(original)),
+            ..Self::from_bytes(decoded)
+        }
+    }
+
+    fn undecoded(self, encoding: &str) -> Self {
+        Self {
+            undecoded: Some(encoding.to_ascii_lowercase()),
+            ..self
+        }
+    }
+
+    fn decoded_spooled(encoding: String, original: &[u8], size: u64, download: String) -> Self {
+        Self {
+            encoding: Some(encoding),
+            original: Some(Base64.encode(original)),
+            ..Self::spooled(size, download)
+        }
+    }
+
+    fn spooled(size: u64, download: String) -> Self {
+        Self {
+            raw: None,
+            base64: None,
+            size,
+            download: Some(download),
+            encoding: None,
+            original: None,
+            undecoded: None,
+            fields: None,
+            parts: None,
+            json: None,
+            json_error: None,
+        }
+    }
+
+    /// Parses form and JSON bodies.
+    async fn parse(&mut self, content_type: Option<&ContentType>, bytes: &[u8]) {
+        let Some(content_type) = content_type else {
+            return;
+        };
+        if json::is_json(content_type) {
+            match json::parse(bytes) {
+                Ok(json) => self.json = Some(json),
+                Err(e) => self.json_error = Some(e.to_string()),
+            }
+        } else if content_type.is_form() {
+            self.fields = Some(form::fields(bytes));
+        } else if content_type.is_form_data() {
+            let Some(boundary) = content_type.param("boundary") else {
+                println!("Multipart body without boundary");
+                return;
+            };
+            match form::parts(bytes, boundary).await {
+                Ok(parts) => self.parts = Some(parts),
+                Err(e) => println!("Could not parse multipart body: {e}"),
+            }
+        }
+    }
+}
+
+impl RequestData {
+    pub fn id(&self) -> Uuid {
+        self.id
+    }
+
+    pub fn method(&self) -> Method {
+        self.method
+    }
+
+    /// Whether the body was cut off at the body limit.
+    pub fn truncated(&self) -> bool {
+        self.complete == Some(false)
+    }
+
+    pub fn seq(&self) -> Option<i64> {
+        self.seq
+    }
+
+    pub fn set_seq(&mut self, seq: i64) {
+        self.seq = Some(seq);
+    }
+
+    pub fn awaits_response(&self) -> bool {
+        self.awaits_response
+    }
+
+    pub fn set_awaits_response(&mut self, awaits_response: bool) {
+        self.awaits_response = awaits_response;
+    }
+
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    pub fn body_raw(&self) -> Option<&str> {
+        self.body.as_ref().and_then(|b| b.raw.as_deref())
+    }
+
+    /// Whether the endpoint rejects the request for lacking a valid signature.
+    pub fn rejected(&self) -> bool {
+        self.reject_unsigned && self.signature != Some(Verdict::Valid)
+    }
+
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    pub fn query_value(&self, name: &str) -> Option<String> {
+        self.uri
+            .query()?
+            .segments()
+            .find(|(n, _)| *n == name)
+            .map(|(_, v)| v.to_owned())
+    }
+}
+
+#[cfg(test)]
+impl RequestData {
+    /// A request to `uri` below `/send/<id>` with an inline body.
+    pub fn for_test(method: Method, uri: &str, headers: &[(&str, &str)], body: &str) -> Self {
+        let uri = Origin::parse_owned(uri.to_owned()).unwrap();
+        let path = format!(
+            "/{}",
+            uri.path().segments().skip(2).collect::<Vec<_>>().join("/")
+        );
+        RequestData {
+            id: Uuid::new_v4(),
+            method,
+            content_type: None,
+            body: Some(Body::from_bytes(body.as_bytes())),
+            complete: Some(true),
+            headers: headers
+                .iter()
+                .map(|(name, value)| (name.to_string(), value.to_string()))
+                .collect(),
+            cookies: MultiMap::new(),
+            uri,
+            path,
+            remote: RemoteInfo {
+                host: None,
+                remote_ip: None,
+                header_ip: None,
+                client_ip: None,
+            },
+            signature: None,
+            reject_unsigned: false,
+            seq: None,
+            awaits_response: false,
+            time: current_iso(),
+        }
     }
 }
 
@@ -61,35 +291,33 @@ pub struct RemoteInfo {
     client_ip: Option<IpAddr>,
 }
 
-#[rocket::async_trait]
-impl<'r> FromData<'r> for RequestData {
-    type Error = (); // TODO
-
-    async fn from_data(req: &'r Request<'_>, data: Data<'r>) -> Outcome<'r, Self> {
+impl RequestData {
+    /// Captures the request with the settings of its endpoint.
+    pub async fn read(req: &Request<'_>, data: Data<'_>, settings: Settings) -> Self {
         let time = current_iso();
-        let mut headers = MultiMap::new();
+        let headers = captured_headers(req.headers());
 
-        for header in req.headers().iter() {
-            headers.insert(header.name.to_string(), header.value.to_string());
-        }
-
-        let body = match data.open(16.kibibytes()).into_bytes().await {
-            Ok(s) => Some(s),
+        let id = Uuid::new_v4();
+        let mut verifier = settings
+            .signature
+            .as_ref()
+            .map(|config| Verifier::new(config, req.headers()));
+        let (body, complete) = match read_body(
+            req,
+            data,
+            id,
+            settings.body_limit,
+            verifier.as_mut().and_then(|v| v.as_mut().ok()),
+        )
+        .await
+        {
+            Ok((body, complete)) => (Some(body), Some(complete)),
             Err(e) => {
                 println!("Error! {e}");
-                None
+                spool::remove(&[id.to_string()]).await;
+                (None, None)
             }
         };
-        let mut complete = None;
-
-        let body = match body {
-            Some(b) => {
-                println!("body: {:?}", b);
-                complete = Some(b.is_complete());
-                Some(Body::from_bytes(&b.value))
-            }
-            None => None,
-        };
 
         let mut cookies = MultiMap::new();
 
@@ -97,7 +325,8 @@ impl<'r> FromData<'r> for RequestData {
             cookies.insert(c.name().to_string(), c.value().to_string());
         }
 
-        Outcome::Success(RequestData {
+        RequestData {
+            id,
             method: req.method(),
             content_type: req
                 .content_type()
@@ -107,18 +336,216 @@ impl<'r> FromData<'r> for RequestData {
             headers,
             cookies,
             uri: req.uri().clone().into_owned(),
+            path: sub_path(req),
             remote: RemoteInfo {
                 host: req.host().cloned().into_owned(),
                 remote_ip: req.remote(),
                 header_ip: req.real_ip(),
                 client_ip: req.client_ip(),
             },
+            signature: verifier.map(|v| v.map_or_else(|verdict| verdict, Verifier::finish)),
+            seq: None,
+            awaits_response: false,
+            reject_unsigned: settings.signature.is_some_and(|config| config.reject()),
             time,
-        })
+        }
     }
 }
 
+/// Settings of the endpoint the request is sent to that affect reading it.
+pub struct Settings {
+    /// Capped by the body limit of the server.
+    body_limit: u64,
+    signature: Option<SignatureConfig>,
+}
+
+/// Loads the settings of the endpoint. Fails if they cannot be loaded, so a
+/// signature is never skipped.
+pub async fn settings(db: &mut SqliteConnection, id: &str) -> Result<Settings, Status> {
+    let server = CONFIG.body_limit();
+    let (limit, signature) = match sqlx::query_as::<_, (Option<i64>, Option<String>)>(
+        "SELECT body_limit, signature FROM auth WHERE id = ?;",
+    )
+    .bind(id)
+    .fetch_one(db)
+    .await
+    {
+        Ok(row) => row,
+        Err(sqlx::Error::RowNotFound) => return Err(Status::NotFound),
+        Err(e) => {
+            eprintln!("Could not load settings of {id}: {e}");
+            return Err(Status::InternalServerError);
+        }
+    };
+    let signature = match signature
+        .map(|json| serde_json::from_str(&json))
+        .transpose()
+    {
+        Ok(signature) => signature,
+        Err(e) => {
+            eprintln!("Could not parse signature config of {id}: {e}");
+            return Err(Status::InternalServerError);
+        }
+    };
+    Ok(Settings {
+        body_limit: limit.map_or(server, |limit| (limit.max(0) as u64).min(server)),
+        signature,
+    })
+}
+
+/// Reads the body up to the body limit. Bodies up to the inline limit are kept
+/// in memory, larger ones are streamed to the spool directory, like decoded
+/// bodies that outgrow it. The kept bytes are fed to the signature verifier.
+/// Returns the body and whether it was read completely.
+async fn read_body(
+    req: &Request<'_>,
+    data: Data<'_>,
+    id: Uuid,
+    limit: u64,
+    mut verifier: Option<&mut Verifier>,
+) -> io::Result<(Body, bool)> {
+    let inline = CONFIG.inline_body_limit();
+    // One byte more than the limit is read to tell whether the body was cut off.
+    let mut stream = data.open((limit + 1).bytes());
+
+    let mut bytes = Vec::new();
+    (&mut stream)
+        .take(inline + 1)
+        .read_to_end(&mut bytes)
+        .await?;
+    if bytes.len() as u64 <= inline {
+        let complete = bytes.len() as u64 <= limit;
+        bytes.truncate(limit as usize);
+        if let Some(verifier) = verifier {
+            verifier.update(&bytes);
+        }
+        let (mut body, readable) = match req.headers().get_one("Content-Encoding") {
+            // A cut off body cannot be decoded.
+            Some(encoding) if complete => match encoding::decode(encoding, &bytes, limit) {
+                Ok(Some(decoded)) if decoded.len() as u64 > inline => {
+                    spool::write(id, &decoded).await?;
+                    let body = Body::decoded_spooled(
+                        encoding.to_ascii_lowercase(),
+                        &bytes,
+                        decoded.len() as u64,
+                        download_url(req, id),
+                    );
+                    return Ok((body, complete));
+                }
+                Ok(Some(decoded)) => {
+                    let body = Body::decoded(encoding.to_ascii_lowercase(), &bytes, &decoded);
+                    bytes = decoded;
+                    (body, true)
+                }
+                Ok(None) => (Body::from_bytes(&bytes), true),
+                Err(e) => {
+                    println!("Could not decode body: {e}");
+                    (Body::from_bytes(&bytes).undecoded(encoding), false)
+                }
+            },
+            Some(encoding) if encoding::is_encoded(encoding) => {
+                (Body::from_bytes(&bytes).undecoded(encoding), false)
+            }
+            Some(_) => (Body::from_bytes(&bytes), true),
+            None => (Body::from_bytes(&bytes), true),
+        };
+        if readable {
+            body.parse(req.content_type(), &bytes).await;
+        }
+        return Ok((body, complete));
+    }
+
+    let path = spool::path(id);
+    let mut file = File::create(&path).await?;
+    file.write_all(&bytes).await?;
+    if let Some(verifier) = verifier.as_mut() {
+        verifier.update(&bytes);
+    }
+    let mut size = bytes.len() as u64;
+    let mut chunk = vec![0; 64 * 1024];
+    loop {
+        let n = stream.read(&mut chunk).await?;
+        if n == 0 {
+            break;
+        }
+        file.write_all(&chunk[..n]).await?;
+        if let Some(verifier) = verifier.as_mut() {
+            verifier.update(&chunk[..(limit.saturating_sub(size) as usize).min(n)]);
+        }
+        size += n as u64;
+    }
+    let complete = size <= limit;
+    if !complete {
+        file.set_len(limit).await?;
+    }
+    file.flush().await?;
+
+    let body = Body::spooled(size.min(limit), download_url(req, id));
+    match req.headers().get_one("Content-Encoding") {
+        Some(encoding) if encoding::is_encoded(encoding) => {
+            Ok((body.undecoded(encoding), complete))
+        }
+        _ => Ok((body, complete)),
+    }
+}
+
+fn download_url(req: &Request<'_>, id: Uuid) -> String {
+    match req.param::<&str>(1) {
+        Some(Ok(endpoint)) => spool::download_url(endpoint, id),
+        _ => String::new(),
+    }
+}
+
+/// Copies the request headers, leaving out the credentials for the server, so
+/// subscribers with the read token never see the send token.
+fn captured_headers(headers: &HeaderMap<'_>) -> MultiMap<String, String> {
+    headers
+        .iter()
+        .filter(|header| !is_credential_header(header.name.as_str()))
+        .map(|header| (header.name.to_string(), header.value.to_string()))
+        .collect()
+}
+
+/// The part of the path after `/send/<id>`, always starting with a slash.
+fn sub_path(req: &Request<'_>) -> String {
+    let mut path = String::new();
+    for segment in req.routed_segments(2..) {
+        path.push('/');
+        path.push_str(segment);
+    }
+    if path.is_empty() {
+        path.push('/');
+    }
+    path
+}
+
 fn current_iso() -> String {
     let now: DateTime<Utc> = SystemTime::now().into();
     now.to_rfc3339()
 }
+
+#[cfg(test)]
+mod tests {
+    use rocket::http::Header;
+
+    use super::*;
+
+    #[test]
+    fn credentials_are_not_captured() {
+        let mut headers = HeaderMap::new();
+        headers.add(Header::new("X-Auth", "send-token"));
+        headers.add(Header::new("x-auth", "owner-token"));
+        headers.add(Header::new("Proxy-Authorization", "Basic Zm9vOmJhcg=="));
+        headers.add(Header::new("Content-Type", "application/json"));
+        headers.add(Header::new("X-Custom", "a"));
+        headers.add(Header::new("X-Custom", "b"));
+
+        let captured = captured_headers(&headers);
+        assert!(captured
+            .keys()
+            .all(|name| !name.eq_ignore_ascii_case("x-auth")
+                && !name.eq_ignore_ascii_case("proxy-authorization")));
+        assert_eq!(captured.get_vec("X-Custom").unwrap(), &["a", "b"]);
+        assert_eq!(captured.get("Content-Type").unwrap(), "application/json");
+    }
+}
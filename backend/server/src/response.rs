@@ -0,0 +1,410 @@
+use std::{collections::HashMap, io::Cursor, time::Duration};
+
+use rocket::{
+    http::{Method, Status},
+    response::{self, Responder},
+    serde::json::Json,
+    tokio::time::sleep,
+    Request, Response,
+};
+use rocket_db_pools::{
+    sqlx::{self, Row, SqliteConnection},
+    Connection,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use shared::{path_matches, CONNECTION_HEADERS};
+
+use crate::{
+    auth::{AuthDb, AuthService, Owner},
+    request_data::RequestData,
+};
+
+const MAX_DELAY_MS: u64 = 30_000;
+
+fn default_status() -> u16 {
+    202
+}
+
+/// A canned answer for requests captured on `/send/<id>`. The first rule whose
+/// `method`, `path` and `query` match the request is applied.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ResponseRule {
+    #[serde(default)]
+    method: Option<Method>,
+    /// Sub-path prefix below `/send/<id>` the rule applies to.
+    #[serde(default)]
+    path: Option<String>,
+    /// Name of a query parameter that has to be present for the rule to match.
+    #[serde(default)]
+    query: Option<String>,
+    #[serde(default = "default_status")]
+    status: u16,
+    #[serde(default)]
+    headers: HashMap<String, String>,
+    /// Body template, see [`render`] for the supported placeholders.
+    #[serde(default)]
+    body: Option<String>,
+    #[serde(default)]
+    delay_ms: Option<u64>,
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ResponseRules {
+    rules: Vec<ResponseRule>,
+}
+
+impl ResponseRule {
+    fn matches(&self, input: &RequestData) -> bool {
+        self.method.is_none_or(|m| m == input.method())
+            && self
+                .path
+                .as_deref()
+                .is_none_or(|prefix| path_matches(prefix, input.path()))
+            && self
+                .query
+                .as_deref()
+                .is_none_or(|name| input.query_value(name).is_some())
+    }
+}
+
+pub struct Reply {
+    status: Status,
+    headers: Vec<(String, String)>,
+    body: Option<Vec<u8>>,
+}
+
+impl Reply {
+    pub fn new(status: Status, headers: Vec<(String, String)>, body: Option<Vec<u8>>) -> Self {
+        Reply {
+            status,
+            headers,
+            body,
+        }
+    }
+}
+
+impl From<Status> for Reply {
+    fn from(status: Status) -> Self {
+        Reply {
+            status,
+            headers: vec![],
+            body: None,
+        }
+    }
+}
+
+/// The status of an answer. Informational ones cannot answer a request.
+pub fn final_status(code: u16) -> Option<Status> {
+    (200..600).contains(&code).then(|| Status::new(code))
+}
+
+/// Answers are served from the origin of the server, so they may neither set
+/// cookies there nor choose how browsers treat their content.
+const ORIGIN_HEADERS: &[&str] = &["set-cookie", "set-cookie2", "x-content-type-options"];
+
+/// Whether browsers would render content of the type as a document or run it
+/// as script.
+fn is_active(content_type: &str) -> bool {
+    let essence = content_type
+        .split(';')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_ascii_lowercase();
+    essence.contains("html")
+        || essence.contains("xml")
+        || essence.contains("javascript")
+        || essence.contains("ecmascript")
+}
+
+/// Drops the headers an answer may not set and serves active content as text.
+fn sanitize(headers: Vec<(String, String)>) -> Vec<(String, String)> {
+    let mut headers: Vec<(String, String)> = headers
+        .into_iter()
+        .filter(|(name, _)| {
+            let name = name.to_ascii_lowercase();
+            !CONNECTION_HEADERS.contains(&name.as_str()) && !ORIGIN_HEADERS.contains(&name.as_str())
+        })
+        .map(|(name, value)| {
+            if name.eq_ignore_ascii_case("content-type") && is_active(&value) {
+                (name, "text/plain; charset=utf-8".to_owned())
+            } else {
+                (name, value)
+            }
+        })
+        .collect();
+    headers.push(("X-Content-Type-Options".to_owned(), "nosniff".to_owned()));
+    headers
+}
+
+impl<'r> Responder<'r, 'static> for Reply {
+    fn respond_to(self, _: &'r Request<'_>) -> response::Result<'static> {
+        let mut res = Response::build();
+        res.status(self.status);
+        for (name, value) in sanitize(self.headers) {
+            res.raw_header_adjoin(name, value);
+        }
+        if let Some(body) = self.body {
+            res.sized_body(body.len(), Cursor::new(body));
+        }
+        res.ok()
+    }
+}
+
+/// Builds the reply for a captured request from the rules of its endpoint,
+/// waiting for the configured delay. Falls back to `202 Accepted`.
+pub async fn reply(rules: Option<ResponseRules>, id: &str, input: &RequestData) -> Reply {
+    let rule = match rules
+        .into_iter()
+        .flat_map(|r| r.rules)
+        .find(|rule| rule.matches(input))
+    {
+        Some(rule) => rule,
+        None => return Status::Accepted.into(),
+    };
+
+    if let Some(delay) = rule.delay_ms {
+        sleep(Duration::from_millis(delay.min(MAX_DELAY_MS))).await;
+    }
+
+    let Some(status) = final_status(rule.status) else {
+        eprintln!("Response rule of {id} has invalid status {}", rule.status);
+        return Status::InternalServerError.into();
+    };
+    Reply {
+        status,
+        headers: rule
+            .headers
+            .iter()
+            .map(|(name, value)| (name.clone(), render(value, id, input)))
+            .collect(),
+        body: rule
+            .body
+            .as_deref()
+            .map(|body| render(body, id, input).into_bytes()),
+    }
+}
+
+/// Replaces `{{...}}` placeholders in a template. Supported are `method`, `id`,
+/// `requestId`, `path`, `body`, `query.<name>`, `header.<name>` and `json.<path>`, where
+/// `<path>` is a dot separated list of object keys and array indices into a
+/// JSON body. Unknown placeholders render as an empty string.
+pub fn render(template: &str, id: &str, input: &RequestData) -> String {
+    let mut out = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find("{{") {
+        out.push_str(&rest[..start]);
+        let tail = &rest[start + 2..];
+        let Some(end) = tail.find("}}") else {
+            rest = &rest[start..];
+            break;
+        };
+        out.push_str(&placeholder(tail[..end].trim(), id, input));
+        rest = &tail[end + 2..];
+    }
+    out.push_str(rest);
+    out
+}
+
+fn placeholder(key: &str, id: &str, input: &RequestData) -> String {
+    match key.split_once('.') {
+        None => match key {
+            "method" => input.method().as_str().to_owned(),
+            "id" => id.to_owned(),
+            "requestId" => input.id().to_string(),
+            "path" => input.path().to_owned(),
+            "body" => input.body_raw().unwrap_or_default().to_owned(),
+            _ => String::new(),
+        },
+        Some(("query", name)) => input.query_value(name).unwrap_or_default(),
+        Some(("header", name)) => input.header(name).unwrap_or_default().to_owned(),
+        Some(("json", path)) => input
+            .body_raw()
+            .and_then(|body| serde_json::from_str::<Value>(body).ok())
+            .and_then(|json| {
+                path.split('.')
+                    .try_fold(&json, |v, segment| match v {
+                        Value::Array(a) => a.get(segment.parse::<usize>().ok()?),
+                        _ => v.get(segment),
+                    })
+                    .map(|v| match v {
+                        Value::String(s) => s.clone(),
+                        other => other.to_string(),
+                    })
+            })
+            .unwrap_or_default(),
+        Some(_) => String::new(),
+    }
+}
+
+pub async fn load(db: &mut SqliteConnection, id: &str) -> Option<ResponseRules> {
+    match sqlx::query("SELECT response FROM auth WHERE id = ?;")
+        .bind(id)
+        .fetch_optional(db)
+        .await
+    {
+        Ok(row) => row
+            .and_then(|row| row.try_get::<Option<String>, _>("response").ok().flatten())
+            .and_then(|json| serde_json::from_str(&json).ok()),
+        Err(e) => {
+            eprintln!("Could not load response rules: {e}");
+            None
+        }
+    }
+}
+
+async fn save(db: &mut SqliteConnection, id: &str, rules: Option<&ResponseRules>) -> Status {
+    let json = rules.map(|rules| serde_json::to_string(rules).unwrap_or_default());
+    match sqlx::query("UPDATE auth SET response = ? WHERE id = ?;")
+        .bind(json)
+        .bind(id)
+        .execute(db)
+        .await
+    {
+        Ok(_) => Status::NoContent,
+        Err(e) => {
+            eprintln!("Could not save response rules: {e}");
+            Status::InternalServerError
+        }
+    }
+}
+
+#[get("/endpoints/<id>/response")]
+pub async fn get_response(
+    id: &str,
+    mut auth: AuthService<Owner>,
+    mut db: Connection<AuthDb>,
+) -> Result<Json<ResponseRules>, Status> {
+    auth.check(id).await?;
+    Ok(Json(load(&mut db, id).await.unwrap_or_default()))
+}
+
+#[put("/endpoints/<id>/response", format = "json", data = "<rules>")]
+pub async fn set_response(
+    id: &str,
+    mut auth: AuthService<Owner>,
+    mut db: Connection<AuthDb>,
+    rules: Json<ResponseRules>,
+) -> Status {
+    if let Err(s) = auth.check(id).await {
+        return s;
+    }
+    if rules.rules.iter().any(|r| final_status(r.status).is_none()) {
+        return Status::UnprocessableEntity;
+    }
+    save(&mut db, id, Some(&rules)).await
+}
+
+#[delete("/endpoints/<id>/response")]
+pub async fn delete_response(
+    id: &str,
+    mut auth: AuthService<Owner>,
+    mut db: Connection<AuthDb>,
+) -> Status {
+    if let Err(s) = auth.check(id).await {
+        return s;
+    }
+    save(&mut db, id, None).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn input() -> RequestData {
+        RequestData::for_test(
+            Method::Post,
+            "/send/abcdefgh/hooks/push?ref=main&empty=",
+            &[("X-Event", "push"), ("Content-Type", "application/json")],
+            r#"{"repo":{"name":"req","tags":["a","b"]},"count":2}"#,
+        )
+    }
+
+    #[test]
+    fn render_replaces_placeholders() {
+        let input = input();
+        assert_eq!(
+            render("{{method}} {{ id }} {{path}}", "abcdefgh", &input),
+            "POST abcdefgh /hooks/push"
+        );
+        assert_eq!(
+            render("{{requestId}}", "abcdefgh", &input),
+            input.id().to_string()
+        );
+        assert_eq!(
+            render("{{body}}", "abcdefgh", &input),
+            input.body_raw().unwrap()
+        );
+        assert_eq!(render("a {{unknown}}b", "abcdefgh", &input), "a b");
+        assert_eq!(
+            render("{{path}} {{path", "abcdefgh", &input),
+            "/hooks/push {{path"
+        );
+        assert_eq!(
+            render("no placeholders", "abcdefgh", &input),
+            "no placeholders"
+        );
+    }
+
+    #[test]
+    fn placeholder_reads_query_headers_and_json() {
+        let input = input();
+        assert_eq!(placeholder("query.ref", "abcdefgh", &input), "main");
+        assert_eq!(placeholder("query.empty", "abcdefgh", &input), "");
+        assert_eq!(placeholder("query.missing", "abcdefgh", &input), "");
+        assert_eq!(placeholder("header.x-event", "abcdefgh", &input), "push");
+        assert_eq!(placeholder("header.missing", "abcdefgh", &input), "");
+        assert_eq!(placeholder("json.repo.name", "abcdefgh", &input), "req");
+        assert_eq!(placeholder("json.repo.tags.1", "abcdefgh", &input), "b");
+        assert_eq!(placeholder("json.count", "abcdefgh", &input), "2");
+        assert_eq!(
+            placeholder("json.repo.tags", "abcdefgh", &input),
+            r#"["a","b"]"#
+        );
+        assert_eq!(placeholder("json.repo.tags.x", "abcdefgh", &input), "");
+        assert_eq!(placeholder("json.missing", "abcdefgh", &input), "");
+        assert_eq!(placeholder("cookie.session", "abcdefgh", &input), "");
+    }
+
+    #[test]
+    fn answers_cannot_set_origin_headers() {
+        let headers = sanitize(vec![
+            ("Set-Cookie".to_owned(), "session=x".to_owned()),
+            ("X-Content-Type-Options".to_owned(), "sniff".to_owned()),
+            ("Transfer-Encoding".to_owned(), "chunked".to_owned()),
+            (
+                "Content-Type".to_owned(),
+                "text/html; charset=utf-8".to_owned(),
+            ),
+            ("X-Custom".to_owned(), "a".to_owned()),
+        ]);
+        assert_eq!(
+            headers,
+            [
+                ("Content-Type", "text/plain; charset=utf-8"),
+                ("X-Custom", "a"),
+                ("X-Content-Type-Options", "nosniff"),
+            ]
+            .map(|(name, value)| (name.to_owned(), value.to_owned()))
+        );
+        assert_eq!(
+            sanitize(vec![(
+                "Content-Type".to_owned(),
+                "application/json".to_owned()
+            )])[0]
+                .1,
+            "application/json"
+        );
+    }
+
+    #[test]
+    fn informational_status_is_rejected() {
+        assert_eq!(final_status(101), None);
+        assert_eq!(final_status(200), Some(Status::Ok));
+        assert_eq!(final_status(599).map(|s| s.code), Some(599));
+        assert_eq!(final_status(600), None);
+    }
+}
@@ -0,0 +1,387 @@
+use chrono::Utc;
+use hmac::{Hmac, Mac};
+use rocket::{
+    http::{HeaderMap, Status},
+    serde::json::Json,
+};
+use rocket_db_pools::{
+    sqlx::{self, SqliteConnection},
+    Connection,
+};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+
+use base64::{engine::general_purpose::STANDARD as Base64, Engine as _};
+
+use crate::auth::{AuthDb, AuthService, Owner};
+
+type HmacSha256 = Hmac<Sha256>;
+
+fn default_tolerance() -> i64 {
+    300
+}
+
+fn default_header() -> String {
+    "X-Signature".to_owned()
+}
+
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum Scheme {
+    /// `X-Hub-Signature-256: sha256=<hex>` over the body.
+    Github,
+    /// `Stripe-Signature: t=<timestamp>,v1=<hex>` over `<timestamp>.<body>`.
+    Stripe,
+    /// `X-Slack-Signature: v0=<hex>` over `v0:<timestamp>:<body>`, with the
+    /// timestamp taken from `X-Slack-Request-Timestamp`.
+    Slack,
+    /// HMAC-SHA256 of the body in a configurable header.
+    Hmac,
+}
+
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum Encoding {
+    #[default]
+    Hex,
+    Base64,
+}
+
+/// How requests to an endpoint are signed.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SignatureConfig {
+    scheme: Scheme,
+    secret: String,
+    /// Maximum age in seconds of the signed timestamp (Stripe and Slack).
+    #[serde(default = "default_tolerance")]
+    tolerance: i64,
+    /// Answer requests without a valid signature with `401 Unauthorized`
+    /// instead of storing and relaying them.
+    #[serde(default)]
+    reject: bool,
+    /// Header carrying the signature for the `hmac` scheme.
+    #[serde(default = "default_header")]
+    header: String,
+    /// Encoding of the signature for the `hmac` scheme.
+    #[serde(default)]
+    encoding: Encoding,
+}
+
+impl SignatureConfig {
+    pub fn reject(&self) -> bool {
+        self.reject
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum Verdict {
+    Valid,
+    Invalid,
+    Missing,
+}
+
+/// Computes the signature of a request while its body is read.
+pub struct Verifier {
+    mac: HmacSha256,
+    expected: Vec<Vec<u8>>,
+}
+
+impl Verifier {
+    /// Prepares the verification from the request headers, failing early if
+    /// the signature is missing, malformed or its timestamp out of tolerance.
+    pub fn new(config: &SignatureConfig, headers: &HeaderMap<'_>) -> Result<Self, Verdict> {
+        let header = |name: &str| headers.get_one(name).ok_or(Verdict::Missing);
+        let (prefix, expected) = match config.scheme {
+            Scheme::Github => {
+                let signature = header("X-Hub-Signature-256")?;
+                let hex = signature.strip_prefix("sha256=").ok_or(Verdict::Invalid)?;
+                (String::new(), vec![decode_hex(hex)?])
+            }
+            Scheme::Stripe => {
+                let mut timestamp = None;
+                let mut expected = vec![];
+                for (key, value) in header("Stripe-Signature")?
+                    .split(',')
+                    .filter_map(|item| item.trim().split_once('='))
+                {
+                    match key {
+                        "t" => timestamp = Some(value),
+                        "v1" => expected.push(decode_hex(value)?),
+                        _ => {}
+                    }
+                }
+                let timestamp = timestamp.ok_or(Verdict::Invalid)?;
+                check_tolerance(timestamp, config.tolerance)?;
+                (format!("{timestamp}."), expected)
+            }
+            Scheme::Slack => {
+                let signature = header("X-Slack-Signature")?;
+                let timestamp = header("X-Slack-Request-Timestamp")?;
+                check_tolerance(timestamp, config.tolerance)?;
+                let hex = signature.strip_prefix("v0=").ok_or(Verdict::Invalid)?;
+                (format!("v0:{timestamp}:"), vec![decode_hex(hex)?])
+            }
+            Scheme::Hmac => {
+                let signature = header(&config.header)?;
+                let signature = signature.strip_prefix("sha256=").unwrap_or(signature);
+                let expected = match config.encoding {
+                    Encoding::Hex => decode_hex(signature)?,
+                    Encoding::Base64 => Base64.decode(signature).map_err(|_| Verdict::Invalid)?,
+                };
+                (String::new(), vec![expected])
+            }
+        };
+        if expected.is_empty() {
+            return Err(Verdict::Missing);
+        }
+
+        let mut mac =
+            HmacSha256::new_from_slice(config.secret.as_bytes()).map_err(|_| Verdict::Invalid)?;
+        mac.update(prefix.as_bytes());
+        Ok(Verifier { mac, expected })
+    }
+
+    pub fn update(&mut self, bytes: &[u8]) {
+        self.mac.update(bytes);
+    }
+
+    /// Compares the signature in constant time.
+    pub fn finish(self) -> Verdict {
+        if self
+            .expected
+            .iter()
+            .any(|expected| self.mac.clone().verify_slice(expected).is_ok())
+        {
+            Verdict::Valid
+        } else {
+            Verdict::Invalid
+        }
+    }
+}
+
+fn decode_hex(hex: &str) -> Result<Vec<u8>, Verdict> {
+    hex::decode(hex.trim()).map_err(|_| Verdict::Invalid)
+}
+
+fn check_tolerance(timestamp: &str, tolerance: i64) -> Result<(), Verdict> {
+    let timestamp: i64 = timestamp.trim().parse().map_err(|_| Verdict::Invalid)?;
+    if (Utc::now().timestamp() - timestamp).abs() > tolerance {
+        return Err(Verdict::Invalid);
+    }
+    Ok(())
+}
+
+pub async fn load(db: &mut SqliteConnection, id: &str) -> Option<SignatureConfig> {
+    match sqlx::query_scalar::<_, Option<String>>("SELECT signature FROM auth WHERE id = ?;")
+        .bind(id)
+        .fetch_optional(db)
+        .await
+    {
+        Ok(json) => json
+            .flatten()
+            .and_then(|json| serde_json::from_str(&json).ok()),
+        Err(e) => {
+            eprintln!("Could not load signature config: {e}");
+            None
+        }
+    }
+}
+
+async fn save(db: &mut SqliteConnection, id: &str, config: Option<&SignatureConfig>) -> Status {
+    let json = config.map(|config| serde_json::to_string(config).unwrap_or_default());
+    match sqlx::query("UPDATE auth SET signature = ? WHERE id = ?;")
+        .bind(json)
+        .bind(id)
+        .execute(db)
+        .await
+    {
+        Ok(_) => Status::NoContent,
+        Err(e) => {
+            eprintln!("Could not save signature config: {e}");
+            Status::InternalServerError
+        }
+    }
+}
+
+#[get("/endpoints/<id>/signature")]
+pub async fn get_signature(
+    id: &str,
+    mut auth: AuthService<Owner>,
+    mut db: Connection<AuthDb>,
+) -> Result<Option<Json<SignatureConfig>>, Status> {
+    auth.check(id).await?;
+    Ok(load(&mut db, id).await.map(Json))
+}
+
+#[put("/endpoints/<id>/signature", format = "json", data = "<config>")]
+pub async fn set_signature(
+    id: &str,
+    mut auth: AuthService<Owner>,
+    mut db: Connection<AuthDb>,
+    config: Json<SignatureConfig>,
+) -> Status {
+    if let Err(s) = auth.check(id).await {
+        return s;
+    }
+    if config.secret.is_empty() {
+        return Status::UnprocessableEntity;
+    }
+    save(&mut db, id, Some(&config)).await
+}
+
+#[delete("/endpoints/<id>/signature")]
+pub async fn delete_signature(
+    id: &str,
+    mut auth: AuthService<Owner>,
+    mut db: Connection<AuthDb>,
+) -> Status {
+    if let Err(s) = auth.check(id).await {
+        return s;
+    }
+    save(&mut db, id, None).await
+}
+
+#[cfg(test)]
+mod tests {
+    use rocket::http::Header;
+
+    use super::*;
+
+    fn config(scheme: Scheme, secret: &str) -> SignatureConfig {
+        SignatureConfig {
+            scheme,
+            secret: secret.to_owned(),
+            tolerance: default_tolerance(),
+            reject: false,
+            header: default_header(),
+            encoding: Encoding::Hex,
+        }
+    }
+
+    fn verify(config: &SignatureConfig, headers: &[(&str, String)], body: &[u8]) -> Verdict {
+        let mut map = HeaderMap::new();
+        for (name, value) in headers {
+            map.add(Header::new(name.to_string(), value.clone()));
+        }
+        match Verifier::new(config, &map) {
+            Ok(mut verifier) => {
+                verifier.update(body);
+                verifier.finish()
+            }
+            Err(verdict) => verdict,
+        }
+    }
+
+    fn sign(secret: &str, message: &str) -> String {
+        let mut mac = HmacSha256::new_from_slice(secret.as_bytes()).unwrap();
+        mac.update(message.as_bytes());
+        hex::encode(mac.finalize().into_bytes())
+    }
+
+    #[test]
+    fn github() {
+        // The example from the GitHub documentation on validating deliveries.
+        let config = config(Scheme::Github, "It's a Secret to Everybody");
+        let signature = "sha256=757107ea0eb2509fc211221cce984b8a37570b6d7586c22c46f4379c8b043e17";
+        let headers = [("X-Hub-Signature-256", signature.to_owned())];
+        assert_eq!(verify(&config, &headers, b"Hello, World!"), Verdict::Valid);
+        assert_eq!(
+            verify(&config, &headers, b"Hello, World?"),
+            Verdict::Invalid
+        );
+        assert_eq!(verify(&config, &[], b"Hello, World!"), Verdict::Missing);
+        let unprefixed = [("X-Hub-Signature-256", signature[7..].to_owned())];
+        assert_eq!(
+            verify(&config, &unprefixed, b"Hello, World!"),
+            Verdict::Invalid
+        );
+    }
+
+    #[test]
+    fn stripe() {
+        let config = config(Scheme::Stripe, "whsec_test");
+        let t = Utc::now().timestamp();
+        let valid = sign("whsec_test", &format!("{t}.{{\"id\":1}}"));
+        let header = |v1: &str| {
+            [(
+                "Stripe-Signature",
+                format!("t={t},v1={},v1={v1}", "00".repeat(32)),
+            )]
+        };
+        assert_eq!(
+            verify(&config, &header(&valid), b"{\"id\":1}"),
+            Verdict::Valid
+        );
+        assert_eq!(
+            verify(&config, &header(&valid), b"{\"id\":2}"),
+            Verdict::Invalid
+        );
+        let no_v1 = [("Stripe-Signature", format!("t={t},v0={valid}"))];
+        assert_eq!(verify(&config, &no_v1, b"{\"id\":1}"), Verdict::Missing);
+    }
+
+    #[test]
+    fn slack() {
+        let config = config(Scheme::Slack, "8f742231b10e8888abcd99yyyzzz85a5");
+        let t = Utc::now().timestamp();
+        let body = b"token=xyzz0WbapA4vBCDEFasx0q6G&team_id=T1DC2JH3J";
+        let valid = sign(
+            "8f742231b10e8888abcd99yyyzzz85a5",
+            &format!("v0:{t}:{}", String::from_utf8_lossy(body)),
+        );
+        let headers = |t: i64, signature: &str| {
+            [
+                ("X-Slack-Request-Timestamp", t.to_string()),
+                ("X-Slack-Signature", format!("v0={signature}")),
+            ]
+        };
+        assert_eq!(verify(&config, &headers(t, &valid), body), Verdict::Valid);
+        // The timestamp is part of the signed message.
+        assert_eq!(
+            verify(&config, &headers(t - 1, &valid), body),
+            Verdict::Invalid
+        );
+        let unsigned = [("X-Slack-Request-Timestamp", t.to_string())];
+        assert_eq!(verify(&config, &unsigned, body), Verdict::Missing);
+    }
+
+    #[test]
+    fn timestamp_tolerance() {
+        let mut config = config(Scheme::Stripe, "secret");
+        config.tolerance = 60;
+        let now = Utc::now().timestamp();
+        let headers = |t: i64| {
+            let v1 = sign("secret", &format!("{t}.body"));
+            [("Stripe-Signature", format!("t={t},v1={v1}"))]
+        };
+        assert_eq!(verify(&config, &headers(now - 30), b"body"), Verdict::Valid);
+        assert_eq!(verify(&config, &headers(now + 30), b"body"), Verdict::Valid);
+        assert_eq!(
+            verify(&config, &headers(now - 120), b"body"),
+            Verdict::Invalid
+        );
+        assert_eq!(
+            verify(&config, &headers(now + 120), b"body"),
+            Verdict::Invalid
+        );
+        let garbled = [("Stripe-Signature", "t=yesterday,v1=00".to_owned())];
+        assert_eq!(verify(&config, &garbled, b"body"), Verdict::Invalid);
+    }
+
+    #[test]
+    fn hmac_in_custom_header() {
+        let mut config = config(Scheme::Hmac, "secret");
+        config.header = "X-Webhook-Signature".to_owned();
+        config.encoding = Encoding::Base64;
+        let signature = Base64.encode(hex::decode(sign("secret", "payload")).unwrap());
+        let headers = [("X-Webhook-Signature", signature)];
+        assert_eq!(verify(&config, &headers, b"payload"), Verdict::Valid);
+        assert_eq!(verify(&config, &headers, b"payload!"), Verdict::Invalid);
+        assert_eq!(
+            verify(&config, &[("X-Signature", "00".to_owned())], b"payload"),
+            Verdict::Missing
+        );
+    }
+}
@@ -0,0 +1,77 @@
+use std::{io::ErrorKind, path::PathBuf};
+
+use rocket::{fs::NamedFile, http::Status, tokio::fs};
+use rocket_db_pools::{sqlx, Connection};
+use shared::CapturedRequest;
+use uuid::Uuid;
+
+use crate::{
+    auth::{AuthDb, AuthService, Subscriber},
+    CONFIG,
+};
+
+pub(crate) fn init() {
+    std::fs::create_dir_all(CONFIG.spool_path()).expect("Could not create spool directory");
+}
+
+pub fn path(request_id: Uuid) -> PathBuf {
+    CONFIG.spool_path().join(request_id.to_string())
+}
+
+pub fn download_url(id: &str, request_id: Uuid) -> String {
+    format!("/body/{id}/{request_id}")
+}
+
+pub async fn write(request_id: Uuid, bytes: &[u8]) -> std::io::Result<()> {
+    fs::write(path(request_id), bytes).await
+}
+
+/// Deletes the spooled bodies of the given requests, if there are any.
+pub async fn remove(request_ids: &[String]) {
+    for request_id in request_ids {
+        if let Err(e) = fs::remove_file(CONFIG.spool_path().join(request_id)).await {
+            if e.kind() != ErrorKind::NotFound {
+                eprintln!("Could not remove spooled body {request_id}: {e}");
+            }
+        }
+    }
+}
+
+/// Loads the spooled body of a stored request from disk, for exports that
+/// need the content.
+pub async fn inline(request: &mut CapturedRequest) {
+    let Some(body) = request.body.as_mut().filter(|body| body.download.is_some()) else {
+        return;
+    };
+    match fs::read(CONFIG.spool_path().join(&request.id)).await {
+        Ok(bytes) => body.inline(&bytes),
+        Err(e) => eprintln!("Could not read spooled body {}: {e}", request.id),
+    }
+}
+
+#[get("/body/<id>/<request_id>")]
+pub async fn download(
+    id: &str,
+    request_id: &str,
+    mut auth: AuthService<Subscriber, true>,
+    mut db: Connection<AuthDb>,
+) -> Result<NamedFile, Status> {
+    auth.check(id).await?;
+    let request_id = Uuid::parse_str(request_id).map_err(|_| Status::NotFound)?;
+
+    match sqlx::query("SELECT 1 FROM history WHERE endpoint = ? AND request_id = ?;")
+        .bind(id)
+        .bind(request_id.to_string())
+        .fetch_optional(&mut **db)
+        .await
+    {
+        Ok(Some(_)) => NamedFile::open(path(request_id))
+            .await
+            .map_err(|_| Status::NotFound),
+        Ok(None) => Err(Status::NotFound),
+        Err(e) => {
+            eprintln!("Could not look up spooled body: {e}");
+            Err(Status::InternalServerError)
+        }
+    }
+}
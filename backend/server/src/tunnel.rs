@@ -0,0 +1,137 @@
+use std::{sync::Arc, time::Duration};
+
+use dashmap::DashMap;
+use futures_channel::{mpsc::Sender, oneshot};
+use rocket::{http::Status, tokio::time::timeout};
+use shared::protocol::TunnelResponse;
+
+use crate::{
+    response::{final_status, Reply},
+    WsMessage, CONFIG,
+};
+
+/// Subscribers answering captured requests, and the requests waiting for
+/// their answer.
+#[derive(Clone, Default)]
+pub struct Tunnels {
+    /// The channels of the responding subscribers per endpoint.
+    responders: Arc<DashMap<String, Vec<Sender<WsMessage>>>>,
+    /// Keyed by endpoint and request id.
+    pending: Arc<DashMap<(String, String), oneshot::Sender<TunnelResponse>>>,
+}
+
+impl Tunnels {
+    /// Registers the subscriber behind `sender` as responding until the
+    /// returned guard is dropped.
+    pub fn attach(&self, id: &str, sender: Sender<WsMessage>) -> Responding {
+        self.responders
+            .entry(id.to_owned())
+            .or_default()
+            .push(sender.clone());
+        Responding {
+            tunnels: self.clone(),
+            id: id.to_owned(),
+            sender,
+        }
+    }
+
+    /// Whether the subscriber behind `sender` answers requests.
+    pub fn responds(&self, id: &str, sender: &Sender<WsMessage>) -> bool {
+        self.responders
+            .get(id)
+            .is_some_and(|senders| senders.iter().any(|s| s.same_receiver(sender)))
+    }
+
+    /// Waits for an answer to the request if a subscriber of the endpoint
+    /// responds. Has to be called before the request is sent to them.
+    pub fn expect(&self, id: &str, request_id: String) -> Option<Pending> {
+        if !self.responders.contains_key(id) {
+            return None;
+        }
+        let key = (id.to_owned(), request_id);
+        let (sender, receiver) = oneshot::channel();
+        self.pending.insert(key.clone(), sender);
+        Some(Pending {
+            tunnels: self.clone(),
+            key,
+            receiver,
+        })
+    }
+}
+
+/// A subscriber answering the requests of an endpoint.
+pub struct Responding {
+    tunnels: Tunnels,
+    id: String,
+    sender: Sender<WsMessage>,
+}
+
+impl Responding {
+    /// Hands the answer to the waiting request. Returns `false` if no request
+    /// with that id waits, e.g. because it timed out or was answered already.
+    pub fn answer(&self, response: TunnelResponse) -> bool {
+        match self
+            .tunnels
+            .pending
+            .remove(&(self.id.clone(), response.id.clone()))
+        {
+            Some((_, sender)) => sender.send(response).is_ok(),
+            None => false,
+        }
+    }
+}
+
+impl Drop for Responding {
+    fn drop(&mut self) {
+        self.tunnels
+            .responders
+            .remove_if_mut(&self.id, |_, senders| {
+                senders.retain(|sender| !sender.same_receiver(&self.sender));
+                senders.is_empty()
+            });
+    }
+}
+
+/// A request waiting for its answer.
+pub struct Pending {
+    tunnels: Tunnels,
+    key: (String, String),
+    receiver: oneshot::Receiver<TunnelResponse>,
+}
+
+impl Pending {
+    /// The answer of a subscriber, or `504 Gateway Timeout` once the configured
+    /// timeout elapsed.
+    pub async fn reply(mut self) -> Reply {
+        let wait = Duration::from_secs(CONFIG.tunnel_timeout());
+        match timeout(wait, &mut self.receiver).await {
+            Ok(Ok(response)) => reply(response),
+            _ => {
+                println!("No answer to {} within {} s", self.key.1, wait.as_secs());
+                Status::GatewayTimeout.into()
+            }
+        }
+    }
+}
+
+impl Drop for Pending {
+    fn drop(&mut self) {
+        self.tunnels.pending.remove(&self.key);
+    }
+}
+
+fn reply(response: TunnelResponse) -> Reply {
+    let Some(status) = final_status(response.status) else {
+        eprintln!(
+            "Answer to {} has invalid status {}",
+            response.id, response.status
+        );
+        return Status::BadGateway.into();
+    };
+    let headers = response
+        .headers
+        .into_iter()
+        .flat_map(|(name, values)| values.into_iter().map(move |value| (name.clone(), value)))
+        .collect();
+    Reply::new(status, headers, response.body.map(|body| body.bytes()))
+}
@@ -0,0 +1,404 @@
+//! Conversion of captured requests into the HTTP Archive format 1.2, see
+//! <http://www.softwareishard.com/blog/har-12-spec/>. Captured requests have
+//! no response, so every entry carries an empty one with status 0.
+
+use serde::Serialize;
+
+use crate::{is_credential_header, CapturedRequest};
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Har {
+    pub log: Log,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Log {
+    pub version: &'static str,
+    pub creator: Creator,
+    pub entries: Vec<Entry>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Creator {
+    pub name: &'static str,
+    pub version: &'static str,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Entry {
+    pub started_date_time: String,
+    pub time: i64,
+    pub request: Request,
+    pub response: Response,
+    pub cache: Cache,
+    pub timings: Timings,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Request {
+    pub method: String,
+    pub url: String,
+    pub http_version: &'static str,
+    pub cookies: Vec<NameValue>,
+    pub headers: Vec<NameValue>,
+    pub query_string: Vec<NameValue>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub post_data: Option<PostData>,
+    pub headers_size: i64,
+    pub body_size: i64,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct NameValue {
+    pub name: String,
+    pub value: String,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PostData {
+    pub mime_type: String,
+    pub text: String,
+    /// `base64` if `text` holds a binary body in base64, a custom field as
+    /// HAR has no encoding for request bodies.
+    #[serde(rename = "_encoding", skip_serializing_if = "Option::is_none")]
+    pub encoding: Option<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub comment: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Response {
+    pub status: u16,
+    pub status_text: String,
+    pub http_version: &'static str,
+    pub cookies: Vec<NameValue>,
+    pub headers: Vec<NameValue>,
+    pub content: Content,
+    #[serde(rename = "redirectURL")]
+    pub redirect_url: String,
+    pub headers_size: i64,
+    pub body_size: i64,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Content {
+    pub size: i64,
+    pub mime_type: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Cache {}
+
+#[derive(Debug, Serialize)]
+pub struct Timings {
+    pub send: i64,
+    pub wait: i64,
+    pub receive: i64,
+}
+
+const HTTP_VERSION: &str = "HTTP/1.1";
+
+/// Builds an archive with one entry per request, in the given order.
+pub fn to_har(requests: &[CapturedRequest]) -> Har {
+    Har {
+        log: Log {
+            version: "1.2",
+            creator: Creator {
+                name: "request-delivery",
+                version: env!("CARGO_PKG_VERSION"),
+            },
+            entries: requests.iter().map(entry).collect(),
+        },
+    }
+}
+
+fn entry(request: &CapturedRequest) -> Entry {
+    Entry {
+        started_date_time: request.time.clone(),
+        time: 0,
+        request: Request {
+            method: request.method.clone(),
+            url: request.url(),
+            http_version: HTTP_VERSION,
+            cookies: name_values(&request.cookies),
+            // Captured before the server dropped credentials.
+            headers: name_values(
+                request
+                    .headers
+                    .iter()
+                    .filter(|(name, _)| !is_credential_header(name)),
+            ),
+            query_string: request.query().map(query_string).unwrap_or_default(),
+            post_data: post_data(request),
+            headers_size: -1,
+            body_size: request
+                .body
+                .as_ref()
+                .map_or(0, |body| body.sent_size() as i64),
+        },
+        response: Response {
+            status: 0,
+            status_text: String::new(),
+            http_version: HTTP_VERSION,
+            cookies: vec![],
+            headers: vec![],
+            content: Content {
+                size: 0,
+                mime_type: String::new(),
+            },
+            redirect_url: String::new(),
+            headers_size: -1,
+            body_size: -1,
+        },
+        cache: Cache {},
+        timings: Timings {
+            send: 0,
+            wait: 0,
+            receive: 0,
+        },
+    }
+}
+
+fn name_values<'a>(map: impl IntoIterator<Item = (&'a String, &'a Vec<String>)>) -> Vec<NameValue> {
+    map.into_iter()
+        .flat_map(|(name, values)| {
+            values.iter().map(|value| NameValue {
+                name: name.clone(),
+                value: value.clone(),
+            })
+        })
+        .collect()
+}
+
+fn query_string(query: &str) -> Vec<NameValue> {
+    query
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+            NameValue {
+                name: percent_decode(name),
+                value: percent_decode(value),
+            }
+        })
+        .collect()
+}
+
+fn percent_decode(s: &str) -> String {
+    let hex = |byte: u8| (byte as char).to_digit(16).map(|d| d as u8);
+    let bytes = s.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'+' => out.push(b' '),
+            b'%' => match (
+                bytes.get(i + 1).and_then(|&b| hex(b)),
+                bytes.get(i + 2).and_then(|&b| hex(b)),
+            ) {
+                (Some(high), Some(low)) => {
+                    out.push(high << 4 | low);
+                    i += 2;
+                }
+                _ => out.push(b'%'),
+            },
+            byte => out.push(byte),
+        }
+        i += 1;
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+fn post_data(request: &CapturedRequest) -> Option<PostData> {
+    let body = request.body.as_ref()?;
+    // The header keeps parameters like the multipart boundary.
+    let mime_type = request
+        .header("content-type")
+        .map(str::to_owned)
+        .or_else(|| request.content_type.clone())
+        .unwrap_or_default();
+    if let Some(download) = &body.download {
+        return Some(PostData {
+            mime_type,
+            text: String::new(),
+            encoding: None,
+            comment: Some(format!(
+                "Body of {} bytes not included, see {download}",
+                body.sent_size()
+            )),
+        });
+    }
+    let bytes = body.bytes();
+    if bytes.is_empty() {
+        return None;
+    }
+    Some(match String::from_utf8(bytes) {
+        Ok(text) => PostData {
+            mime_type,
+            text,
+            encoding: None,
+            comment: None,
+        },
+        Err(_) => PostData {
+            mime_type,
+            text: body.encoded().to_owned(),
+            encoding: Some("base64"),
+            comment: None,
+        },
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use super::*;
+    use crate::{CapturedBody, CapturedRemote};
+
+    fn request(content_type: Option<&str>, body: Option<CapturedBody>) -> CapturedRequest {
+        let mut headers = BTreeMap::from([
+            ("host".to_owned(), vec!["relay.test".to_owned()]),
+            ("x-auth".to_owned(), vec!["secret".to_owned()]),
+        ]);
+        if let Some(content_type) = content_type {
+            headers.insert("content-type".to_owned(), vec![content_type.to_owned()]);
+        }
+        CapturedRequest {
+            id: "a1".to_owned(),
+            method: "POST".to_owned(),
+            content_type: content_type.map(|ct| ct.split(';').next().unwrap().to_owned()),
+            body,
+            complete: Some(true),
+            headers,
+            cookies: BTreeMap::new(),
+            uri: "/send/ep/hook?a=1&b=x%20y&c+d=%zz&flag&&".to_owned(),
+            path: "/hook".to_owned(),
+            remote: CapturedRemote {
+                host: Some("relay.test".to_owned()),
+                remote_ip: None,
+                header_ip: None,
+                client_ip: None,
+            },
+            signature: None,
+            time: "2024-01-02T03:04:05+00:00".to_owned(),
+        }
+    }
+
+    fn pairs(values: &[NameValue]) -> Vec<(&str, &str)> {
+        values
+            .iter()
+            .map(|nv| (nv.name.as_str(), nv.value.as_str()))
+            .collect()
+    }
+
+    #[test]
+    fn entries_leave_out_credentials() {
+        let har = to_har(&[request(None, None), request(None, None)]);
+        assert_eq!(har.log.version, "1.2");
+        assert_eq!(har.log.entries.len(), 2);
+        let request = &har.log.entries[0].request;
+        assert_eq!(
+            request.url,
+            "http://relay.test/send/ep/hook?a=1&b=x%20y&c+d=%zz&flag&&"
+        );
+        assert_eq!(pairs(&request.headers), [("host", "relay.test")]);
+        assert!(request.post_data.is_none());
+        assert_eq!(request.body_size, 0);
+    }
+
+    #[test]
+    fn query_string_is_decoded() {
+        assert_eq!(
+            pairs(&query_string("a=1&b=x%20y&c+d=%zz&flag&&e=%C3%A4%2")),
+            [
+                ("a", "1"),
+                ("b", "x y"),
+                ("c d", "%zz"),
+                ("flag", ""),
+                ("e", "ä%2"),
+            ]
+        );
+        assert!(query_string("").is_empty());
+    }
+
+    #[test]
+    fn percent_decode_keeps_invalid_escapes() {
+        assert_eq!(percent_decode("a%2Fb+c"), "a/b c");
+        assert_eq!(percent_decode("%"), "%");
+        assert_eq!(percent_decode("%4"), "%4");
+        assert_eq!(percent_decode("100%"), "100%");
+        assert_eq!(percent_decode("%ff"), "\u{fffd}");
+    }
+
+    #[test]
+    fn text_bodies_are_inline() {
+        let request = request(Some("application/json"), Some(CapturedBody::new(b"{}")));
+        let post_data = post_data(&request).unwrap();
+        assert_eq!(post_data.mime_type, "application/json");
+        assert_eq!(post_data.text, "{}");
+        assert_eq!(post_data.encoding, None);
+        assert_eq!(entry(&request).request.body_size, 2);
+    }
+
+    #[test]
+    fn binary_bodies_are_base64() {
+        let request = request(None, Some(CapturedBody::new(&[0, 159, 146, 150])));
+        let post_data = post_data(&request).unwrap();
+        assert_eq!(post_data.text, "AJ+Slg==");
+        assert_eq!(post_data.encoding, Some("base64"));
+        assert_eq!(post_data.mime_type, "");
+    }
+
+    #[test]
+    fn multipart_mime_type_keeps_the_boundary() {
+        let content_type = "multipart/form-data; boundary=XyZ";
+        let request = request(
+            Some(content_type),
+            Some(CapturedBody::new(b"--XyZ\r\n\r\nvalue\r\n--XyZ--\r\n")),
+        );
+        assert_eq!(post_data(&request).unwrap().mime_type, content_type);
+    }
+
+    #[test]
+    fn decoded_bodies_are_exported_as_sent() {
+        let sent = [31, 139, 8, 0, 0, 0, 0, 0, 0, 3];
+        let mut body = CapturedBody::new(b"decoded body");
+        body.encoding = Some("gzip".to_owned());
+        body.original = Some(CapturedBody::new(&sent).base64);
+        let request = request(Some("text/plain"), Some(body));
+        let post_data = post_data(&request).unwrap();
+        assert_eq!(post_data.text, "H4sIAAAAAAAAAw==");
+        assert_eq!(post_data.encoding, Some("base64"));
+        assert_eq!(entry(&request).request.body_size, sent.len() as i64);
+    }
+
+    #[test]
+    fn spooled_bodies_are_referenced() {
+        let body = CapturedBody {
+            raw: String::new(),
+            base64: String::new(),
+            size: 100_000,
+            download: Some("/body/ep/a1".to_owned()),
+            encoding: None,
+            original: None,
+        };
+        let request = request(Some("application/octet-stream"), Some(body));
+        let post_data = post_data(&request).unwrap();
+        assert_eq!(post_data.text, "");
+        assert_eq!(post_data.encoding, None);
+        assert_eq!(
+            post_data.comment.as_deref(),
+            Some("Body of 100000 bytes not included, see /body/ep/a1")
+        );
+        assert_eq!(entry(&request).request.body_size, 100_000);
+    }
+}
@@ -6,6 +6,15 @@ use std::{
 use chrono::NaiveDateTime;
 use serde::Deserialize;
 
+pub mod har;
+pub mod protocol;
+pub mod repro;
+mod request;
+pub use request::{
+    is_credential_header, CapturedBody, CapturedRemote, CapturedRequest, CONNECTION_HEADERS,
+    CREDENTIAL_HEADERS,
+};
+
 #[derive(Debug)]
 pub enum SharedError {
     ConfigParse(serde_ini::de::Error),
@@ -22,13 +31,55 @@ pub struct Config {
     max_age: i64,
     auth_db: String,
     secret_path: PathBuf,
+    #[serde(default = "default_history_size")]
+    history_size: u32,
+    #[serde(default = "default_buffer_max_count")]
+    buffer_max_count: usize,
+    #[serde(default = "default_buffer_max_age")]
+    buffer_max_age: u64,
+    #[serde(default = "default_body_limit")]
+    body_limit: u64,
+    #[serde(default = "default_inline_body_limit")]
+    inline_body_limit: u64,
+    #[serde(default = "default_spool_path")]
+    spool_path: PathBuf,
+    #[serde(default = "default_tunnel_timeout")]
+    tunnel_timeout: u64,
+    #[serde(default)]
+    replay_allow: String,
+}
+
+fn default_history_size() -> u32 {
+    1000
+}
+
+fn default_buffer_max_count() -> usize {
+    50
+}
+
+fn default_buffer_max_age() -> u64 {
+    300
+}
+
+fn default_body_limit() -> u64 {
+    16 * 1024 * 1024
+}
+
+fn default_inline_body_limit() -> u64 {
+    16 * 1024
+}
+
+fn default_spool_path() -> PathBuf {
+    PathBuf::from("./spool")
+}
+
+fn default_tunnel_timeout() -> u64 {
+    30
 }
 
 impl Config {
     pub fn get_epoch(&self) -> NaiveDateTime {
-        NaiveDateTime::parse_from_str(&self.my_epoch, "%Y-%m-%d %T")
-            .expect("Could not parse thing")
-            .into()
+        NaiveDateTime::parse_from_str(&self.my_epoch, "%Y-%m-%d %T").expect("Could not parse thing")
     }
 
     pub fn ui_path(&self) -> &Path {
@@ -50,6 +101,45 @@ impl Config {
     pub fn secret_path(&self) -> &Path {
         &self.secret_path
     }
+
+    /// At least 1, the latest request backs body downloads and poll cursors.
+    pub fn history_size(&self) -> u32 {
+        self.history_size.max(1)
+    }
+
+    pub fn buffer_max_count(&self) -> usize {
+        self.buffer_max_count
+    }
+
+    pub fn buffer_max_age(&self) -> u64 {
+        self.buffer_max_age
+    }
+
+    pub fn body_limit(&self) -> u64 {
+        self.body_limit
+    }
+
+    pub fn inline_body_limit(&self) -> u64 {
+        self.inline_body_limit
+    }
+
+    pub fn spool_path(&self) -> &Path {
+        &self.spool_path
+    }
+
+    pub fn tunnel_timeout(&self) -> u64 {
+        self.tunnel_timeout
+    }
+
+    /// Host names, addresses and CIDR ranges replays may reach although they
+    /// are not public.
+    pub fn replay_allow(&self) -> Vec<&str> {
+        self.replay_allow
+            .split(',')
+            .map(str::trim)
+            .filter(|entry| !entry.is_empty())
+            .collect()
+    }
 }
 
 pub fn read_config<P>(path: P) -> SharedResult<Config>
@@ -60,6 +150,16 @@ where
     serde_ini::from_read(f).map_err(SharedError::ConfigParse)
 }
 
+/// Whether a captured sub-path lies below `prefix`, matching whole segments
+/// only (`/github` matches `/github/push` but not `/githubx`).
+pub fn path_matches(prefix: &str, path: &str) -> bool {
+    let prefix = prefix.trim_end_matches('/');
+    prefix.is_empty()
+        || path
+            .strip_prefix(prefix)
+            .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+}
+
 pub fn custom_timestamp(custom_epoch: NaiveDateTime) -> i64 {
     chrono::offset::Local::now()
         .naive_local()
@@ -0,0 +1,97 @@
+//! Messages on the `/connect/<id>` websocket.
+//!
+//! Clients pick the protocol version with the `v` query parameter, the server
+//! answers with the highest version it supports up to the requested one.
+//! Without `v` the legacy version 0 is used: requests are sent as bare JSON
+//! and everything else is conveyed by close codes.
+//!
+//! From version 1 on every message is a JSON object with a `type` and the
+//! version `v`, e.g. `{"type":"request","v":1,"id":"…","seq":3,"request":{…}}`.
+//! The server starts with a `hello` carrying the negotiated version. Expiry,
+//! revocation and shutdown are announced before the connection is closed with
+//! the same close codes as in version 0 (4001, 4002 and 1001). Clients may
+//! send `ping` messages, which are answered with a `pong` echoing `data`.
+//!
+//! Clients connecting with `respond=true` can answer captured requests: the
+//! server holds a request open while `respond` is set on it, and returns the
+//! first `response` with the request's `id` to the original caller. Without
+//! an answer within the configured timeout the caller gets a `504`. Answering
+//! needs a token that may also send to the endpoint. Answers cannot set
+//! cookies, and HTML, XML and script content is served as plain text.
+
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+
+use crate::CapturedBody;
+
+/// Highest protocol version the server speaks.
+pub const VERSION: u32 = 1;
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Envelope<T> {
+    pub v: u32,
+    #[serde(flatten)]
+    pub message: T,
+}
+
+impl<T> Envelope<T> {
+    pub fn new(v: u32, message: T) -> Self {
+        Envelope { v, message }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum ServerMessage<R> {
+    /// First message on a connection. `respond` tells whether the client may
+    /// answer requests.
+    Hello { endpoint: String, respond: bool },
+    /// A captured request. `id` is the id the server gave the request, `seq`
+    /// its position in the history of the endpoint. `respond` is set if the
+    /// caller waits for a `response`.
+    Request {
+        id: String,
+        seq: Option<i64>,
+        #[serde(default)]
+        respond: bool,
+        request: R,
+    },
+    /// The endpoint expired.
+    Expired,
+    /// The endpoint was deleted or its token rotated.
+    Revoked,
+    /// The server is shutting down, reconnecting later is fine.
+    Shutdown,
+    /// The server closes the connection.
+    Closed,
+    Pong {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        data: Option<String>,
+    },
+    /// A client message could not be understood.
+    Error { message: String },
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum ClientMessage {
+    Ping {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        data: Option<String>,
+    },
+    Response(TunnelResponse),
+}
+
+/// The answer to a captured request, returned to its caller.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TunnelResponse {
+    /// Id of the answered request.
+    pub id: String,
+    pub status: u16,
+    #[serde(default)]
+    pub headers: BTreeMap<String, Vec<String>>,
+    #[serde(default)]
+    pub body: Option<CapturedBody>,
+}
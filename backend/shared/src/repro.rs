@@ -0,0 +1,310 @@
+//! Ready-to-run reproductions of captured requests. Text bodies are inlined,
+//! binary ones are written to [`BODY_FILE`] by a preceding `base64 -d` command.
+
+use std::{fmt, str::FromStr};
+
+use crate::CapturedRequest;
+
+pub const BODY_FILE: &str = "body.bin";
+
+/// Methods with a constant in `reqwest::Method`.
+const STANDARD_METHODS: &[&str] = &[
+    "GET", "POST", "PUT", "DELETE", "HEAD", "OPTIONS", "CONNECT", "PATCH", "TRACE",
+];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Format {
+    Curl,
+    Httpie,
+    Reqwest,
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "curl" => Ok(Format::Curl),
+            "httpie" => Ok(Format::Httpie),
+            "reqwest" => Ok(Format::Reqwest),
+            _ => Err(format!(
+                "unknown format {s}, expected curl, httpie or reqwest"
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Format {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Format::Curl => "curl",
+            Format::Httpie => "httpie",
+            Format::Reqwest => "reqwest",
+        })
+    }
+}
+
+enum ReproBody {
+    None,
+    Text(String),
+    /// Base64 of a binary body.
+    File(String),
+}
+
+impl ReproBody {
+    fn of(request: &CapturedRequest) -> Self {
+        let Some(body) = &request.body else {
+            return ReproBody::None;
+        };
+        let bytes = body.bytes();
+        if bytes.is_empty() {
+            return ReproBody::None;
+        }
+        match String::from_utf8(bytes) {
+            Ok(text) => ReproBody::Text(text),
+            Err(_) => ReproBody::File(body.encoded().to_owned()),
+        }
+    }
+
+    /// Shell command writing a binary body to [`BODY_FILE`].
+    fn preamble(&self) -> Option<String> {
+        match self {
+            ReproBody::File(base64) => Some(format!(
+                "echo {} | base64 -d > {BODY_FILE}",
+                shell_quote(base64)
+            )),
+            _ => None,
+        }
+    }
+}
+
+/// Renders a reproduction of the request. Spooled bodies have to be inlined
+/// beforehand, otherwise the reproduction is sent without a body.
+pub fn render(request: &CapturedRequest, format: Format) -> String {
+    let body = ReproBody::of(request);
+    let mut out = String::new();
+    match format {
+        Format::Curl | Format::Httpie => {
+            if let Some(preamble) = body.preamble() {
+                out.push_str(&preamble);
+                out.push('\n');
+            }
+            if format == Format::Curl {
+                out.push_str(&curl(request, &body));
+            } else {
+                out.push_str(&httpie(request, &body));
+            }
+        }
+        Format::Reqwest => {
+            if let Some(preamble) = body.preamble() {
+                out.push_str(&format!("// Create {BODY_FILE} with: {preamble}\n"));
+            }
+            out.push_str(&reqwest(request, &body));
+        }
+    }
+    out.push('\n');
+    out
+}
+
+fn curl(request: &CapturedRequest, body: &ReproBody) -> String {
+    let mut args = vec!["curl".to_owned()];
+    // With `-X HEAD` curl would wait for a body that never comes.
+    if request.method == "HEAD" {
+        args.push("-I".to_owned());
+    } else {
+        args.push(format!("-X {}", shell_quote(&request.method)));
+    }
+    args.push(shell_quote(&request.url()));
+    for (name, value) in request.end_to_end_headers() {
+        args.push(format!("-H {}", shell_quote(&format!("{name}: {value}"))));
+    }
+    match body {
+        ReproBody::None => {}
+        // Unlike `--data-binary`, `--data-raw` does not read files for a leading `@`.
+        ReproBody::Text(text) => args.push(format!("--data-raw {}", shell_quote(text))),
+        ReproBody::File(_) => args.push(format!("--data-binary @{BODY_FILE}")),
+    }
+    args.join(" \\\n  ")
+}
+
+fn httpie(request: &CapturedRequest, body: &ReproBody) -> String {
+    let mut args = vec![
+        "http".to_owned(),
+        shell_quote(&request.method),
+        shell_quote(&request.url()),
+    ];
+    for (name, value) in request.end_to_end_headers() {
+        args.push(shell_quote(&format!("{name}:{value}")));
+    }
+    match body {
+        ReproBody::None => {}
+        ReproBody::Text(text) => args.insert(1, format!("--raw {}", shell_quote(text))),
+        ReproBody::File(_) => args.push(format!("< {BODY_FILE}")),
+    }
+    args.join(" \\\n  ")
+}
+
+fn reqwest(request: &CapturedRequest, body: &ReproBody) -> String {
+    let mut out = String::from("let response = reqwest::Client::new()\n");
+    let method = if STANDARD_METHODS.contains(&request.method.as_str()) {
+        format!("reqwest::Method::{}", request.method)
+    } else {
+        format!("reqwest::Method::from_bytes(b{:?})?", request.method)
+    };
+    out.push_str(&format!("    .request({method}, {:?})\n", request.url()));
+    for (name, value) in request.end_to_end_headers() {
+        out.push_str(&format!("    .header({name:?}, {value:?})\n"));
+    }
+    match body {
+        ReproBody::None => {}
+        ReproBody::Text(text) => out.push_str(&format!("    .body({text:?})\n")),
+        ReproBody::File(_) => out.push_str(&format!("    .body(std::fs::read({BODY_FILE:?})?)\n")),
+    }
+    out.push_str("    .send()\n    .await?;");
+    out
+}
+
+/// Quotes an argument for POSIX shells, leaving it bare if that is safe.
+pub fn shell_quote(arg: &str) -> String {
+    if !arg.is_empty()
+        && arg
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b"-_./:=@%+,".contains(&b))
+    {
+        return arg.to_owned();
+    }
+    format!("'{}'", arg.replace('\'', r"'\''"))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::BTreeMap, process::Command};
+
+    use super::*;
+    use crate::{CapturedBody, CapturedRemote};
+
+    fn request(method: &str, body: Option<&[u8]>) -> CapturedRequest {
+        CapturedRequest {
+            id: "a1".to_owned(),
+            method: method.to_owned(),
+            content_type: None,
+            body: body.map(CapturedBody::new),
+            complete: Some(true),
+            headers: BTreeMap::from([
+                ("host".to_owned(), vec!["relay.test".to_owned()]),
+                ("x-auth".to_owned(), vec!["secret".to_owned()]),
+                ("x-note".to_owned(), vec!["it's here".to_owned()]),
+            ]),
+            cookies: BTreeMap::new(),
+            uri: "/send/ep/hook?a=1&b=2".to_owned(),
+            path: "/hook".to_owned(),
+            remote: CapturedRemote {
+                host: Some("relay.test".to_owned()),
+                remote_ip: None,
+                header_ip: None,
+                client_ip: None,
+            },
+            signature: None,
+            time: String::new(),
+        }
+    }
+
+    /// What a POSIX shell makes of the quoted argument.
+    fn unquoted(quoted: &str) -> String {
+        let output = Command::new("sh")
+            .arg("-c")
+            .arg(format!("printf %s {quoted}"))
+            .output()
+            .expect("sh is available");
+        String::from_utf8(output.stdout).unwrap()
+    }
+
+    #[test]
+    fn shell_quote_round_trips() {
+        for arg in [
+            "plain",
+            "",
+            "it's",
+            "'",
+            "two\nlines\n",
+            "@body.bin",
+            "$HOME `id` $(id) \\ \"x\" *",
+            "a b\tc",
+        ] {
+            assert_eq!(unquoted(&shell_quote(arg)), arg, "{arg:?}");
+        }
+        assert_eq!(shell_quote("@file"), "@file");
+        assert_eq!(shell_quote(""), "''");
+        assert_eq!(shell_quote("it's"), r"'it'\''s'");
+    }
+
+    #[test]
+    fn curl_sends_text_literally() {
+        let out = render(&request("POST", Some(b"@/etc/passwd\nit's")), Format::Curl);
+        assert_eq!(
+            out,
+            "curl \\\n  -X POST \\\n  'http://relay.test/send/ep/hook?a=1&b=2' \\\n  \
+             -H 'x-note: it'\\''s here' \\\n  --data-raw '@/etc/passwd\nit'\\''s'\n"
+        );
+    }
+
+    #[test]
+    fn curl_heads_without_body() {
+        let out = render(&request("HEAD", None), Format::Curl);
+        assert!(out.starts_with("curl \\\n  -I \\\n"), "{out}");
+        assert!(!out.contains("--data"), "{out}");
+        assert!(!out.contains("secret"), "{out}");
+    }
+
+    #[test]
+    fn binary_bodies_go_through_a_file() {
+        let request = request("PUT", Some(&[0, 159, 146, 150]));
+        let curl = render(&request, Format::Curl);
+        assert!(
+            curl.starts_with("echo AJ+Slg== | base64 -d > body.bin\ncurl"),
+            "{curl}"
+        );
+        assert!(curl.ends_with("--data-binary @body.bin\n"), "{curl}");
+
+        let httpie = render(&request, Format::Httpie);
+        assert!(
+            httpie.starts_with("echo AJ+Slg== | base64 -d > body.bin\nhttp"),
+            "{httpie}"
+        );
+        assert!(httpie.ends_with("< body.bin\n"), "{httpie}");
+
+        let reqwest = render(&request, Format::Reqwest);
+        assert!(
+            reqwest.starts_with("// Create body.bin with: echo AJ+Slg== | base64 -d > body.bin\n"),
+            "{reqwest}"
+        );
+        assert!(
+            reqwest.contains(".body(std::fs::read(\"body.bin\")?)"),
+            "{reqwest}"
+        );
+    }
+
+    #[test]
+    fn httpie_passes_text_raw() {
+        let out = render(&request("POST", Some(b"line 1\nline 2")), Format::Httpie);
+        assert_eq!(
+            out,
+            "http \\\n  --raw 'line 1\nline 2' \\\n  POST \\\n  \
+             'http://relay.test/send/ep/hook?a=1&b=2' \\\n  'x-note:it'\\''s here'\n"
+        );
+    }
+
+    #[test]
+    fn reqwest_escapes_rust_literals() {
+        let out = render(&request("PURGE", Some(b"say \"hi\"\n")), Format::Reqwest);
+        assert_eq!(
+            out,
+            "let response = reqwest::Client::new()\n\
+             \x20   .request(reqwest::Method::from_bytes(b\"PURGE\")?, \
+             \"http://relay.test/send/ep/hook?a=1&b=2\")\n\
+             \x20   .header(\"x-note\", \"it's here\")\n\
+             \x20   .body(\"say \\\"hi\\\"\\n\")\n\
+             \x20   .send()\n    .await?;\n"
+        );
+    }
+}
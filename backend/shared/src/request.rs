@@ -0,0 +1,170 @@
+use std::collections::BTreeMap;
+
+use base64::{engine::general_purpose::STANDARD as Base64, Engine as _};
+use serde::{Deserialize, Serialize};
+
+/// Headers that describe the connection to the relay server rather than the
+/// captured request and are therefore left out when it is sent again.
+pub const CONNECTION_HEADERS: &[&str] = &[
+    "host",
+    "content-length",
+    "connection",
+    "keep-alive",
+    "transfer-encoding",
+    "te",
+    "trailer",
+    "upgrade",
+    "proxy-authorization",
+    "proxy-connection",
+    "x-auth",
+];
+
+/// Headers carrying credentials for the relay server or a proxy in front of
+/// it. The server never captures them.
+pub const CREDENTIAL_HEADERS: &[&str] = &["x-auth", "proxy-authorization"];
+
+pub fn is_credential_header(name: &str) -> bool {
+    CREDENTIAL_HEADERS
+        .iter()
+        .any(|credential| credential.eq_ignore_ascii_case(name))
+}
+
+/// Client side view of a request captured by the server, as it is sent over
+/// the websocket and stored in the history.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CapturedRequest {
+    pub id: String,
+    pub method: String,
+    pub content_type: Option<String>,
+    pub body: Option<CapturedBody>,
+    pub complete: Option<bool>,
+    #[serde(default)]
+    pub headers: BTreeMap<String, Vec<String>>,
+    #[serde(default)]
+    pub cookies: BTreeMap<String, Vec<String>>,
+    pub uri: String,
+    /// Sub-path the request was sent to below `/send/<id>`
+    #[serde(default)]
+    pub path: String,
+    pub remote: CapturedRemote,
+    /// Signature verdict (`valid`, `invalid` or `missing`) if the endpoint
+    /// verifies signatures
+    #[serde(default)]
+    pub signature: Option<String>,
+    pub time: String,
+}
+
+/// Bodies above the inline limit of the server carry no content, only the
+/// `download` path relative to the server they can be fetched from. If the
+/// server decoded the `Content-Encoding`, `raw` and `base64` hold the decoded
+/// body and `original` the body as sent.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CapturedBody {
+    #[serde(default)]
+    pub raw: String,
+    #[serde(default)]
+    pub base64: String,
+    #[serde(default)]
+    pub size: u64,
+    pub download: Option<String>,
+    #[serde(default)]
+    pub encoding: Option<String>,
+    #[serde(default)]
+    pub original: Option<String>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CapturedRemote {
+    pub host: Option<String>,
+    pub remote_ip: Option<String>,
+    pub header_ip: Option<String>,
+    pub client_ip: Option<String>,
+}
+
+impl CapturedRequest {
+    pub fn uri_path(&self) -> &str {
+        self.uri.split_once('?').map_or(&self.uri, |(path, _)| path)
+    }
+
+    pub fn query(&self) -> Option<&str> {
+        self.uri.split_once('?').map(|(_, query)| query)
+    }
+
+    /// The absolute URL the request was sent to. The scheme is only known if a
+    /// proxy in front of the server sets `X-Forwarded-Proto`.
+    pub fn url(&self) -> String {
+        let scheme = self.header("x-forwarded-proto").unwrap_or("http");
+        let host = self.remote.host.as_deref().unwrap_or("localhost");
+        format!("{scheme}://{host}{}", self.uri)
+    }
+
+    /// The first value of a header, by case-insensitive name.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(header, _)| header.eq_ignore_ascii_case(name))
+            .and_then(|(_, values)| values.first())
+            .map(String::as_str)
+    }
+
+    /// Every header value except [`CONNECTION_HEADERS`].
+    pub fn end_to_end_headers(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.headers
+            .iter()
+            .filter(|(name, _)| !CONNECTION_HEADERS.contains(&name.to_ascii_lowercase().as_str()))
+            .flat_map(|(name, values)| values.iter().map(|value| (name.as_str(), value.as_str())))
+    }
+
+    pub fn body_bytes(&self) -> Vec<u8> {
+        self.body
+            .as_ref()
+            .map(CapturedBody::bytes)
+            .unwrap_or_default()
+    }
+}
+
+impl CapturedBody {
+    pub fn new(bytes: &[u8]) -> Self {
+        let mut body = CapturedBody {
+            raw: String::new(),
+            base64: String::new(),
+            size: 0,
+            download: None,
+            encoding: None,
+            original: None,
+        };
+        body.inline(bytes);
+        body
+    }
+
+    /// The body as sent, still encoded if the server decoded it.
+    pub fn bytes(&self) -> Vec<u8> {
+        Base64
+            .decode(self.encoded())
+            .unwrap_or_else(|_| self.raw.clone().into_bytes())
+    }
+
+    /// Base64 of the body as sent.
+    pub fn encoded(&self) -> &str {
+        self.original.as_deref().unwrap_or(&self.base64)
+    }
+
+    /// Size of the body as sent, `size` being the decoded size.
+    pub fn sent_size(&self) -> u64 {
+        self.original
+            .as_ref()
+            .and_then(|original| Base64.decode(original).ok())
+            .map_or(self.size, |bytes| bytes.len() as u64)
+    }
+
+    /// Sets the content to `bytes`, dropping any download reference.
+    pub fn inline(&mut self, bytes: &[u8]) {
+        self.raw = String::from_utf8_lossy(bytes).into_owned();
+        self.base64 = Base64.encode(bytes);
+        self.size = bytes.len() as u64;
+        self.download = None;
+    }
+}
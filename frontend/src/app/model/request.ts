@@ -9,20 +9,42 @@ export type Method =
   | 'CONNECT'
   | 'PATCH';
 
+export interface FormPart {
+  name?: string;
+  filename?: string;
+  contentType?: string;
+  size: number;
+  raw?: string;
+  base64: string;
+}
+
 export interface RequestData {
   method: Method;
   contentType?: string;
-  body?: { raw: string; base64: string };
+  body?: {
+    raw?: string;
+    base64?: string;
+    size: number;
+    download?: string;
+    encoding?: string;
+    original?: string;
+    fields?: Record<string, string[]>;
+    parts?: FormPart[];
+    json?: { value: any; pretty: string; canonical: string };
+    jsonError?: string;
+  };
   complete?: boolean;
   headers: Record<string, string[]>;
   cookies: Record<string, string[]>;
   uri: string;
+  path: string;
   remote: {
     host?: string;
     remoteIp?: string;
     headerIp?: string;
     clientIp?: string;
   };
+  signature?: 'valid' | 'invalid' | 'missing';
   time: Date;
 }
 
@@ -32,6 +54,7 @@ export let DEFAULT_REQUEST: RequestData = {
   body: {
     raw: '{\r\n    "Hallo": "Hallo",\r\n    "1": 1,\r\n    "nested": {\r\n        "nested": "A",\r\n        "1:1": 1\r\n    },\r\n    "arr": [\r\n        "A",\r\n        1,\r\n        "sldf",\r\n        {\r\n            "a": "a",\r\n            "b": "c"\r\n        }\r\n    ]\r\n}',
     base64: 'Example',
+    size: 238,
   },
   complete: true,
   headers: {
@@ -53,6 +76,7 @@ export let DEFAULT_REQUEST: RequestData = {
     Cookie_1: ['value2'],
   },
   uri: '/send/cb0688e1-cd7c-4d02-a5c2-03608b97593c?test=21232',
+  path: '/',
   remote: {
     host: 'localhost:18234',
     remoteIp: '127.0.0.1:55017',
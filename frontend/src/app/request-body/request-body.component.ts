@@ -33,7 +33,13 @@ export class RequestBodyComponent {
     if (!this.requestEvent.body?.raw) return {};
     switch (this.bodyType) {
       case BodyType.Json:
-        return JSON.parse(this.requestEvent.body.raw);
+        if (this.requestEvent.body.jsonError) {
+          return { error: this.requestEvent.body.jsonError };
+        }
+        return (
+          this.requestEvent.body.json?.value ??
+          JSON.parse(this.requestEvent.body.raw)
+        );
       case BodyType.Xml:
         return new XMLParser().parse(this.requestEvent.body.raw);
       default: